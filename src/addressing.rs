@@ -0,0 +1,38 @@
+use crate::register_manager::{RegisterError, RegisterType};
+
+/// How the address of a request maps to the keys of data.json
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum Addressing {
+    /// The request carries the key itself, e.g. 40001 for the first holding register
+    #[default]
+    Legacy,
+    /// Offsets from the start of the table, e.g. 0 for 40001
+    ZeroBased,
+    /// Register numbers within the table, e.g. 1 for 40001
+    OneBased,
+}
+
+impl Addressing {
+    pub fn parse(value: &str) -> Option<Self> {
+        clap::ValueEnum::from_str(value, true).ok()
+    }
+
+    /// Translates the address of a request for `cnt` registers into the offset of the first
+    /// register within its table
+    pub fn offset(self, registers_type: &RegisterType, addr: u16, cnt: u16) -> Result<u16, RegisterError> {
+        let first = match registers_type {
+            RegisterType::Coils => 1,
+            RegisterType::Inputs => 10001,
+            RegisterType::InputRegisters => 30001,
+            RegisterType::HoldingRegisters => 40001,
+        };
+
+        match self {
+            // only the first 9999 registers of a table have a 5-digit key, the last one included
+            Addressing::Legacy => addr.checked_sub(first).filter(|&offset| offset as u32 + cnt.max(1) as u32 <= 9999),
+            Addressing::ZeroBased => Some(addr),
+            Addressing::OneBased => addr.checked_sub(1),
+        }
+        .ok_or(RegisterError::OutOfBounds)
+    }
+}
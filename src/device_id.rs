@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+
+use tokio_modbus::{bytes::Bytes, ExceptionCode};
+
+use crate::json::{JsonError, Layout, DEVICE_ID_KEY};
+
+/// Function code of Encapsulated Interface Transport
+pub const FUNCTION_CODE: u8 = 0x2B;
+
+/// MEI type of Read Device Identification
+const MEI_TYPE: u8 = 0x0E;
+
+/// Bytes of the response PDU before the first object
+const HEADER_LEN: usize = 7;
+
+/// Largest PDU, function code included
+const MAX_PDU_LEN: usize = 253;
+
+/// Longest object value that still fits a response on its own
+const MAX_VALUE_LEN: usize = MAX_PDU_LEN - HEADER_LEN - 2;
+
+/// Names of the basic (0x00-0x02) and regular (0x03-0x06) objects
+const OBJECT_NAMES: [&str; 7] = [
+    "vendor_name",
+    "product_code",
+    "revision",
+    "vendor_url",
+    "product_name",
+    "model_name",
+    "user_application_name",
+];
+
+/// Objects answered through Read Device Identification (FC43 / MEI 14)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceIdentification {
+    objects: BTreeMap<u8, Vec<u8>>,
+}
+
+impl DeviceIdentification {
+    /// Reads the `device_identification` section, if the file has one.
+    ///
+    /// Standard objects are given by name, extended ones (0x80-0xFF) by
+    /// their object ID in decimal or `0x` hex.
+    pub fn from_layout(layout: &Layout) -> Result<Option<Self>, JsonError> {
+        let Some(section) = layout.get(DEVICE_ID_KEY) else {
+            return Ok(None);
+        };
+
+        let section = section
+            .as_object()
+            .ok_or_else(|| JsonError::Invalid(format!("'{}' must be an object", DEVICE_ID_KEY)))?;
+
+        let mut objects = BTreeMap::new();
+
+        for (key, value) in section {
+            let id = Self::object_id(key)
+                .ok_or_else(|| JsonError::Invalid(format!("Unknown device identification object '{}'", key)))?;
+
+            let value = value
+                .as_str()
+                .ok_or_else(|| JsonError::Invalid(format!("Device identification object '{}' must be a string", key)))?;
+
+            if value.len() > MAX_VALUE_LEN {
+                return Err(JsonError::Invalid(format!(
+                    "Device identification object '{}' is longer than {} bytes",
+                    key, MAX_VALUE_LEN
+                )));
+            }
+
+            objects.insert(id, value.as_bytes().to_vec());
+        }
+
+        if let Some(missing) = OBJECT_NAMES[..3].iter().enumerate().find(|(id, _)| !objects.contains_key(&(*id as u8))) {
+            return Err(JsonError::Invalid(format!("Missing device identification object '{}'", missing.1)));
+        }
+
+        Ok(Some(DeviceIdentification { objects }))
+    }
+
+    fn object_id(key: &str) -> Option<u8> {
+        if let Some(id) = OBJECT_NAMES.iter().position(|&name| name == key) {
+            return Some(id as u8);
+        }
+
+        match key.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16).ok(),
+            None => key.parse().ok(),
+        }
+        .filter(|&id| id >= 0x80)
+    }
+
+    /// Conformity level, always including individual access
+    fn conformity(&self) -> u8 {
+        match self.objects.keys().next_back() {
+            Some(&id) if id >= 0x80 => 0x83,
+            Some(&id) if id >= 0x03 => 0x82,
+            _ => 0x81,
+        }
+    }
+
+    /// Answers the data of an FC43 request, without the function code
+    pub fn respond(&self, data: &[u8]) -> Result<Bytes, ExceptionCode> {
+        let &[mei_type, code, object_id] = data else {
+            return Err(ExceptionCode::IllegalDataValue);
+        };
+
+        if mei_type != MEI_TYPE {
+            return Err(ExceptionCode::IllegalFunction);
+        }
+
+        let last = match code {
+            0x01 => 0x02,
+            0x02 => 0x7F,
+            0x03 => 0xFF,
+            0x04 => {
+                let value = self.objects.get(&object_id).ok_or(ExceptionCode::IllegalDataAddress)?;
+
+                let mut response = vec![MEI_TYPE, code, self.conformity(), 0x00, 0x00, 1];
+                Self::push_object(&mut response, object_id, value);
+
+                return Ok(response.into());
+            }
+            _ => return Err(ExceptionCode::IllegalDataValue),
+        };
+
+        // an unknown starting object restarts the stream from the beginning
+        let first = if object_id <= last && self.objects.contains_key(&object_id) {
+            object_id
+        } else {
+            0x00
+        };
+
+        let mut response = vec![MEI_TYPE, code, self.conformity(), 0x00, 0x00, 0];
+
+        for (&id, value) in self.objects.range(first..=last) {
+            // the function code isn't part of the response data
+            if response.len() + 1 + 2 + value.len() > MAX_PDU_LEN {
+                response[3] = 0xFF;
+                response[4] = id;
+                break;
+            }
+
+            Self::push_object(&mut response, id, value);
+            response[5] += 1;
+        }
+
+        Ok(response.into())
+    }
+
+    fn push_object(response: &mut Vec<u8>, id: u8, value: &[u8]) {
+        response.push(id);
+        response.push(value.len() as u8);
+        response.extend_from_slice(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    type Error = Box<dyn std::error::Error>;
+
+    fn device(section: serde_json::Value) -> Result<Option<DeviceIdentification>, JsonError> {
+        DeviceIdentification::from_layout(json!({ DEVICE_ID_KEY: section }).as_object().unwrap())
+    }
+
+    #[test]
+    pub fn test_device_identification() -> Result<(), Error> {
+        let id = device(json!({
+            "vendor_name": "Acme",
+            "product_code": "PM-1",
+            "revision": "1.2",
+            "model_name": "Meter",
+            "0x80": "a".repeat(200),
+            "129": "b".repeat(100),
+        }))
+        .unwrap().unwrap();
+
+        // basic stream
+        let response = id.respond(&[0x0E, 0x01, 0x00]).unwrap();
+        assert_eq!(
+            &response[..],
+            b"\x0E\x01\x83\x00\x00\x03\x00\x04Acme\x01\x04PM-1\x02\x031.2"
+        );
+
+        // regular stream, starting at an object that doesn't exist
+        let response = id.respond(&[0x0E, 0x02, 0x04]).unwrap();
+        assert_eq!(&response[..6], [0x0E, 0x02, 0x83, 0x00, 0x00, 4]);
+        assert!(response.ends_with(b"\x05\x05Meter"));
+
+        // extended stream, pages once the next object no longer fits
+        let response = id.respond(&[0x0E, 0x03, 0x00]).unwrap();
+        assert_eq!(&response[..6], [0x0E, 0x03, 0x83, 0xFF, 0x81, 5]);
+        assert_eq!(response.len() + 1, HEADER_LEN + 24 + 202);
+
+        let response = id.respond(&[0x0E, 0x03, 0x81]).unwrap();
+        assert_eq!(&response[..8], [0x0E, 0x03, 0x83, 0x00, 0x00, 1, 0x81, 100]);
+
+        // individual access
+        let response = id.respond(&[0x0E, 0x04, 0x02]).unwrap();
+        assert_eq!(&response[..], b"\x0E\x04\x83\x00\x00\x01\x02\x031.2");
+
+        assert_eq!(id.respond(&[0x0E, 0x04, 0x03]), Err(ExceptionCode::IllegalDataAddress));
+        assert_eq!(id.respond(&[0x0E, 0x05, 0x00]), Err(ExceptionCode::IllegalDataValue));
+        assert_eq!(id.respond(&[0x0E, 0x01]), Err(ExceptionCode::IllegalDataValue));
+        assert_eq!(id.respond(&[0x0D, 0x01, 0x00]), Err(ExceptionCode::IllegalFunction));
+
+        assert!(DeviceIdentification::from_layout(&Layout::new()).unwrap().is_none());
+        assert!(device(json!({ "vendor_name": "Acme", "product_code": "PM-1" })).is_err());
+        assert!(device(json!({ "vendor_name": "Acme", "product_code": "PM-1", "revision": "1", "0x10": "x" })).is_err());
+        assert!(device(json!({ "vendor_name": "Acme", "product_code": "PM-1", "revision": 1 })).is_err());
+        assert!(device(json!({ "vendor_name": "a".repeat(245), "product_code": "PM-1", "revision": "1" })).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use tokio_modbus::{bytes::Bytes, ExceptionCode, Response};
+
+/// Function code of Diagnostics
+pub const DIAGNOSTICS: u8 = 0x08;
+
+/// Function code of Get Comm Event Counter
+pub const COMM_EVENT_COUNTER: u8 = 0x0B;
+
+const RETURN_QUERY_DATA: u16 = 0x00;
+const RETURN_DIAGNOSTIC_REGISTER: u16 = 0x02;
+const CLEAR_COUNTERS: u16 = 0x0A;
+const BUS_MESSAGE_COUNT: u16 = 0x0B;
+const BUS_COMMUNICATION_ERROR_COUNT: u16 = 0x0C;
+const BUS_EXCEPTION_ERROR_COUNT: u16 = 0x0D;
+const SERVER_MESSAGE_COUNT: u16 = 0x0E;
+const SERVER_NO_RESPONSE_COUNT: u16 = 0x0F;
+const SERVER_NAK_COUNT: u16 = 0x10;
+const SERVER_BUSY_COUNT: u16 = 0x11;
+const BUS_CHARACTER_OVERRUN_COUNT: u16 = 0x12;
+const CLEAR_OVERRUN_COUNTER: u16 = 0x14;
+
+/// Whether an FC08 request, without the function code, resets counters rather than only reading them
+pub fn clears(data: &[u8]) -> bool {
+    match data {
+        [high, low, ..] => matches!(u16::from_be_bytes([*high, *low]), CLEAR_COUNTERS | CLEAR_OVERRUN_COUNTER),
+        _ => false,
+    }
+}
+
+/// Event and message counters of one server, shared by all of its connections.
+///
+/// Counters wrap around at 65535 like those of serial devices.
+#[derive(Debug, Default)]
+pub struct Counters {
+    bus_messages: AtomicU16,
+    bus_errors: AtomicU16,
+    exceptions: AtomicU16,
+    server_messages: AtomicU16,
+    events: AtomicU16,
+}
+
+impl Counters {
+    /// Counts a request addressed to the server
+    pub fn received(&self) {
+        self.bus_messages.fetch_add(1, Ordering::Relaxed);
+        self.server_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a frame that failed its CRC or LRC check
+    pub fn bus_error(&self) {
+        self.bus_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the outcome of a request. Polls of the event counter itself
+    /// don't count as events.
+    pub fn completed<T>(&self, result: &Result<T, ExceptionCode>, event: bool) {
+        match result {
+            Ok(_) if event => {
+                self.events.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(_) => {}
+            Err(_) => {
+                self.exceptions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn clear(&self) {
+        for counter in [&self.bus_messages, &self.bus_errors, &self.exceptions, &self.server_messages, &self.events] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Answers an FC08 request, without the function code
+    pub fn diagnostics(&self, data: &[u8]) -> Result<Response, ExceptionCode> {
+        if data.len() < 2 {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+
+        let sub_function = u16::from_be_bytes([data[0], data[1]]);
+        let query = &data[2..];
+
+        if sub_function == RETURN_QUERY_DATA {
+            return Ok(Response::Custom(DIAGNOSTICS, Bytes::copy_from_slice(data)));
+        }
+
+        // every other supported sub-function takes a single zero word
+        if query != [0, 0] {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+
+        let value = match sub_function {
+            CLEAR_COUNTERS => {
+                self.clear();
+                0
+            }
+            BUS_MESSAGE_COUNT => self.bus_messages.load(Ordering::Relaxed),
+            BUS_COMMUNICATION_ERROR_COUNT => self.bus_errors.load(Ordering::Relaxed),
+            BUS_EXCEPTION_ERROR_COUNT => self.exceptions.load(Ordering::Relaxed),
+            SERVER_MESSAGE_COUNT => self.server_messages.load(Ordering::Relaxed),
+            RETURN_DIAGNOSTIC_REGISTER
+            | CLEAR_OVERRUN_COUNTER
+            | SERVER_NO_RESPONSE_COUNT
+            | SERVER_NAK_COUNT
+            | SERVER_BUSY_COUNT
+            | BUS_CHARACTER_OVERRUN_COUNT => 0,
+            _ => return Err(ExceptionCode::IllegalFunction),
+        };
+
+        let mut response = data[..2].to_vec();
+        response.extend_from_slice(&value.to_be_bytes());
+
+        Ok(Response::Custom(DIAGNOSTICS, response.into()))
+    }
+
+    /// Answers an FC11 request with a ready status and the event count
+    pub fn comm_event_counter(&self, data: &[u8]) -> Result<Response, ExceptionCode> {
+        if !data.is_empty() {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+
+        let mut response = vec![0, 0];
+        response.extend_from_slice(&self.events.load(Ordering::Relaxed).to_be_bytes());
+
+        Ok(Response::Custom(COMM_EVENT_COUNTER, response.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    type Error = Box<dyn std::error::Error>;
+
+    fn diagnostics(counters: &Counters, sub_function: u16) -> Result<Response, ExceptionCode> {
+        let mut data = sub_function.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0, 0]);
+        counters.diagnostics(&data)
+    }
+
+    fn count(value: u16, sub_function: u16) -> Result<Response, ExceptionCode> {
+        let mut data = sub_function.to_be_bytes().to_vec();
+        data.extend_from_slice(&value.to_be_bytes());
+        Ok(Response::Custom(DIAGNOSTICS, data.into()))
+    }
+
+    #[test]
+    pub fn test_counters() -> Result<(), Error> {
+        let counters = Counters::default();
+
+        for result in [Ok(()), Ok(()), Err(ExceptionCode::IllegalDataAddress)] {
+            counters.received();
+            counters.completed(&result, true);
+        }
+        counters.received();
+        counters.completed(&Ok(()), false);
+
+        assert_eq!(diagnostics(&counters, BUS_MESSAGE_COUNT), count(4, BUS_MESSAGE_COUNT));
+        assert_eq!(diagnostics(&counters, SERVER_MESSAGE_COUNT), count(4, SERVER_MESSAGE_COUNT));
+        assert_eq!(diagnostics(&counters, BUS_EXCEPTION_ERROR_COUNT), count(1, BUS_EXCEPTION_ERROR_COUNT));
+        assert_eq!(diagnostics(&counters, BUS_COMMUNICATION_ERROR_COUNT), count(0, BUS_COMMUNICATION_ERROR_COUNT));
+        assert_eq!(
+            counters.comm_event_counter(&[]),
+            Ok(Response::Custom(COMM_EVENT_COUNTER, vec![0, 0, 0, 2].into()))
+        );
+
+        assert_eq!(diagnostics(&counters, CLEAR_COUNTERS), count(0, CLEAR_COUNTERS));
+        assert_eq!(diagnostics(&counters, BUS_MESSAGE_COUNT), count(0, BUS_MESSAGE_COUNT));
+
+        assert_eq!(
+            counters.diagnostics(&[0, 0, 0xA5, 0x37, 0x42]),
+            Ok(Response::Custom(DIAGNOSTICS, vec![0, 0, 0xA5, 0x37, 0x42].into()))
+        );
+        assert_eq!(diagnostics(&counters, 0x01), Err(ExceptionCode::IllegalFunction));
+        assert_eq!(counters.diagnostics(&[0, BUS_MESSAGE_COUNT as u8, 0, 1]), Err(ExceptionCode::IllegalDataValue));
+        assert_eq!(counters.diagnostics(&[0]), Err(ExceptionCode::IllegalDataValue));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use log::{debug, error, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    time::timeout,
+};
+use tokio_modbus::{
+    bytes::{Buf, Bytes, BytesMut},
+    server::Service,
+    ExceptionResponse, Request, SlaveRequest,
+};
+
+use crate::units::{UnitMap, UnitService};
+
+/// How requests are framed on a listener
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum Framing {
+    /// Modbus TCP with an MBAP header
+    #[default]
+    Tcp,
+    /// RTU frames with a CRC, as tunnelled by serial-to-Ethernet converters
+    Rtu,
+    /// ASCII frames with an LRC
+    Ascii,
+}
+
+/// A frame that was received but failed its checksum or couldn't be decoded
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    Crc { expected: u16, actual: u16 },
+    Lrc { expected: u8, actual: u8 },
+    /// Not hex between ':' and CR LF, too short to hold a unit and function code,
+    /// or cut short by a silent interval
+    Malformed,
+    /// Longer than the largest frame of the transport
+    Oversized(usize),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Crc { expected, actual } => {
+                write!(f, "CRC mismatch, expected {:04X} but got {:04X}", expected, actual)
+            }
+            FrameError::Lrc { expected, actual } => {
+                write!(f, "LRC mismatch, expected {:02X} but got {:02X}", expected, actual)
+            }
+            FrameError::Malformed => f.write_str("Malformed frame"),
+            FrameError::Oversized(len) => write!(f, "Oversized frame of {} bytes", len),
+        }
+    }
+}
+
+/// A decoded frame: the unit ID and the PDU
+pub type Frame = (u8, Bytes);
+
+/// Longest RTU frame, unit ID and CRC included
+pub const MAX_RTU_LEN: usize = 256;
+
+/// Longest ASCII frame, ':' and CR LF included
+const MAX_ASCII_LEN: usize = 513;
+
+impl Framing {
+    /// Takes the next complete frame off the front of `buf`, if there is one
+    pub fn decode(self, buf: &mut BytesMut) -> Option<Result<Frame, FrameError>> {
+        match self {
+            Framing::Tcp => None,
+            Framing::Rtu => decode_rtu(buf),
+            Framing::Ascii => decode_ascii(buf),
+        }
+    }
+
+    /// Ends whatever is left in `buf` once the line has been silent for a while.
+    /// RTU frames of unknown length end this way, and broken frames are dropped.
+    pub fn flush(self, buf: &mut BytesMut) -> Option<Result<Frame, FrameError>> {
+        match self {
+            _ if buf.is_empty() => None,
+            Framing::Rtu if buf.len() > MAX_RTU_LEN => {
+                let len = buf.len();
+                buf.clear();
+                Some(Err(FrameError::Oversized(len)))
+            }
+            Framing::Rtu if buf.len() >= 4 => Some(take_rtu(buf, buf.len())),
+            _ => {
+                buf.clear();
+                Some(Err(FrameError::Malformed))
+            }
+        }
+    }
+
+    /// Silence after which a partial frame is flushed on a TCP stream
+    pub fn gap(self) -> Duration {
+        match self {
+            Framing::Ascii => Duration::from_secs(1),
+            _ => Duration::from_millis(100),
+        }
+    }
+
+    pub fn encode(self, slave: u8, pdu: &[u8]) -> Vec<u8> {
+        match self {
+            Framing::Tcp => pdu.to_vec(),
+            Framing::Rtu => encode_rtu(slave, pdu),
+            Framing::Ascii => encode_ascii(slave, pdu),
+        }
+    }
+}
+
+/// CRC-16/MODBUS, sent low byte first
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u16, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xA001,
+            _ => crc >> 1,
+        })
+    })
+}
+
+/// Two's complement of the sum of all bytes
+pub fn lrc(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)).wrapping_neg()
+}
+
+/// Length of the RTU request at the front of `buf`, unit ID and CRC included.
+///
+/// RTU has no length field and a TCP stream has no silent intervals, so the
+/// length follows from the function code. `None` means more bytes are needed
+/// to tell, or that the frame only ends with a silent interval, as do those of
+/// unknown function codes and the echoed data of Return Query Data.
+fn rtu_request_len(buf: &[u8]) -> Option<usize> {
+    let byte_count = |at: usize| buf.get(at).map(|&n| at + 1 + n as usize + 2);
+
+    match buf.get(1)? {
+        0x01..=0x06 => Some(8),
+        0x08 => match buf.get(2..4)? {
+            [0x00, 0x00] => None,
+            _ => Some(8),
+        },
+        0x07 | 0x0B | 0x0C | 0x11 => Some(4),
+        0x0F | 0x10 => byte_count(6),
+        0x16 => Some(10),
+        0x17 => byte_count(10),
+        0x2B => Some(7),
+        _ => None,
+    }
+}
+
+fn decode_rtu(buf: &mut BytesMut) -> Option<Result<Frame, FrameError>> {
+    let len = match rtu_request_len(buf) {
+        Some(len) => len,
+        None if buf.len() > MAX_RTU_LEN => buf.len(),
+        None => return None,
+    };
+
+    if len > MAX_RTU_LEN {
+        buf.clear();
+        return Some(Err(FrameError::Oversized(len)));
+    }
+
+    if buf.len() < len {
+        return None;
+    }
+
+    Some(take_rtu(buf, len))
+}
+
+fn take_rtu(buf: &mut BytesMut, len: usize) -> Result<Frame, FrameError> {
+    let frame = buf.split_to(len);
+    let (data, crc) = frame.split_at(len - 2);
+    let expected = crc16(data);
+    let actual = u16::from_le_bytes([crc[0], crc[1]]);
+
+    if expected != actual {
+        // the rest of the buffer can't be trusted to start on a frame
+        buf.clear();
+        return Err(FrameError::Crc { expected, actual });
+    }
+
+    Ok((data[0], Bytes::copy_from_slice(&data[1..])))
+}
+
+fn encode_rtu(slave: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(pdu.len() + 3);
+    frame.push(slave);
+    frame.extend_from_slice(pdu);
+    frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+    frame
+}
+
+fn decode_ascii(buf: &mut BytesMut) -> Option<Result<Frame, FrameError>> {
+    // anything before the start of a frame is noise
+    match buf.iter().position(|&b| b == b':') {
+        Some(start) => buf.advance(start),
+        None => {
+            buf.clear();
+            return None;
+        }
+    }
+
+    let Some(end) = buf.iter().position(|&b| b == b'\n') else {
+        if buf.len() > MAX_ASCII_LEN {
+            buf.clear();
+            return Some(Err(FrameError::Malformed));
+        }
+        return None;
+    };
+
+    let line = buf.split_to(end + 1);
+    let hex = line[1..].strip_suffix(b"\r\n").unwrap_or(&line[1..end]);
+
+    let bytes: Option<Vec<u8>> = hex.len().is_multiple_of(2)
+        .then(|| {
+            hex.chunks(2)
+                .map(|pair| std::str::from_utf8(pair).ok().and_then(|s| u8::from_str_radix(s, 16).ok()))
+                .collect()
+        })
+        .flatten();
+
+    let bytes = match bytes {
+        Some(bytes) if bytes.len() >= 3 => bytes,
+        _ => return Some(Err(FrameError::Malformed)),
+    };
+
+    let (data, &[actual]) = bytes.split_at(bytes.len() - 1) else {
+        return Some(Err(FrameError::Malformed));
+    };
+    let expected = lrc(data);
+
+    if expected != actual {
+        return Some(Err(FrameError::Lrc { expected, actual }));
+    }
+
+    Some(Ok((data[0], Bytes::copy_from_slice(&data[1..]))))
+}
+
+fn encode_ascii(slave: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut data = vec![slave];
+    data.extend_from_slice(pdu);
+    data.push(lrc(&data));
+
+    let mut frame = vec![b':'];
+    frame.extend(data.iter().flat_map(|b| format!("{:02X}", b).into_bytes()));
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+/// Serves RTU or ASCII framed requests on every connection of `listener`
+pub async fn serve<F>(listener: TcpListener, framing: Framing, unit_map: Arc<UnitMap>, new_service: F) -> std::io::Result<()>
+where
+    F: Fn(SocketAddr) -> UnitService,
+{
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("Accepted {:?} framed connection from {}", framing, addr);
+
+        let service = new_service(addr);
+        let unit_map = unit_map.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = process(stream, framing, framing.gap(), &unit_map, service).await {
+                error!("Connection from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Answers the frames of one stream until it is closed. Bytes left over after
+/// `gap` without new data are flushed as a frame of their own.
+pub async fn process<S>(
+    mut stream: S,
+    framing: Framing,
+    gap: Duration,
+    unit_map: &UnitMap,
+    service: UnitService,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(MAX_ASCII_LEN);
+
+    loop {
+        let silent = if buf.is_empty() {
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Ok(());
+            }
+            false
+        } else {
+            match timeout(gap, stream.read_buf(&mut buf)).await {
+                Ok(Ok(0)) => return Ok(()),
+                Ok(read) => read.map(|_| false)?,
+                Err(_) => true,
+            }
+        };
+
+        let mut frames = Vec::new();
+        while let Some(frame) = framing.decode(&mut buf) {
+            frames.push(frame);
+        }
+        if silent {
+            frames.extend(framing.flush(&mut buf));
+        }
+
+        for frame in frames {
+            reply(&mut stream, framing, frame, unit_map, &service).await?;
+        }
+    }
+}
+
+/// Answers a frame on `stream`, unless it is a broadcast
+pub async fn reply<S>(
+    stream: &mut S,
+    framing: Framing,
+    frame: Result<Frame, FrameError>,
+    unit_map: &UnitMap,
+    service: &UnitService,
+) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    match answer(frame, unit_map, service).await {
+        // broadcasts are processed but never answered
+        Some((0, _)) => debug!("Processed broadcast without responding"),
+        Some((slave, response)) => stream.write_all(&framing.encode(slave, &response)).await?,
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// The unit ID and response PDU answering a frame, if it gets one. Frames
+/// that failed to decode are counted as bus errors by every unit.
+pub async fn answer(frame: Result<Frame, FrameError>, unit_map: &UnitMap, service: &UnitService) -> Option<Frame> {
+    let (slave, pdu) = match frame {
+        Ok(frame) => frame,
+        Err(e) => {
+            warn!("Dropped frame: {}", e);
+            for manager in unit_map.managers() {
+                manager.counters().bus_error();
+            }
+            return None;
+        }
+    };
+
+    let request = match Request::try_from(pdu) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Dropped undecodable request for unit {}: {}", slave, e);
+            return None;
+        }
+    };
+
+    let function = request.function_code();
+    let response: Bytes = match service.call(SlaveRequest { slave, request }).await {
+        Ok(Some(response)) => response.into(),
+        Ok(None) => return None,
+        Err(exception) => ExceptionResponse { function, exception }.into(),
+    };
+
+    Some((slave, response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    type Error = Box<dyn std::error::Error>;
+
+    #[test]
+    pub fn test_rtu() -> Result<(), Error> {
+        // read 2 holding registers from 0 on unit 1
+        let request = [0x01, 0x03, 0x00, 0x00, 0x00, 0x02, 0xC4, 0x0B];
+        assert_eq!(crc16(&request[..6]), 0x0BC4);
+        assert_eq!(encode_rtu(1, &request[1..6]), request);
+
+        // a frame split across reads, followed by the start of the next
+        let mut buf = BytesMut::from(&request[..5]);
+        assert_eq!(Framing::Rtu.decode(&mut buf), None);
+        buf.extend_from_slice(&request[5..]);
+        buf.extend_from_slice(&request[..2]);
+        assert_eq!(Framing::Rtu.decode(&mut buf), Some(Ok((1, Bytes::from_static(&[0x03, 0x00, 0x00, 0x00, 0x02])))));
+        assert_eq!(buf.len(), 2);
+
+        // write 2 registers, sized by its byte count
+        let mut buf = BytesMut::from(&encode_rtu(2, &[0x10, 0x00, 0x01, 0x00, 0x02, 0x04, 0x00, 0x0A, 0x01, 0x02])[..]);
+        assert_eq!(buf.len(), 13);
+        assert!(matches!(Framing::Rtu.decode(&mut buf), Some(Ok((2, _)))));
+
+        let mut buf = BytesMut::from(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x02, 0xC4, 0x0C][..]);
+        assert_eq!(Framing::Rtu.decode(&mut buf), Some(Err(FrameError::Crc { expected: 0x0BC4, actual: 0x0CC4 })));
+        assert!(buf.is_empty());
+
+        // unknown function codes end with a silent interval
+        let mut buf = BytesMut::from(&encode_rtu(1, &[0x41, 0x01, 0x02])[..]);
+        assert_eq!(Framing::Rtu.decode(&mut buf), None);
+        assert_eq!(Framing::Rtu.flush(&mut buf), Some(Ok((1, Bytes::from_static(&[0x41, 0x01, 0x02])))));
+        assert_eq!(Framing::Rtu.flush(&mut buf), None);
+
+        // as do those echoing any amount of data with Return Query Data
+        let echo = [0x08, 0x00, 0x00, 0x12, 0x34, 0x56, 0x78];
+        let mut buf = BytesMut::from(&encode_rtu(1, &echo)[..]);
+        assert_eq!(Framing::Rtu.decode(&mut buf), None);
+        assert_eq!(Framing::Rtu.flush(&mut buf), Some(Ok((1, Bytes::copy_from_slice(&echo)))));
+
+        // other diagnostics have a single data word
+        let mut buf = BytesMut::from(&encode_rtu(1, &[0x08, 0x00, 0x0C, 0x00, 0x00])[..]);
+        assert!(matches!(Framing::Rtu.decode(&mut buf), Some(Ok((1, _)))));
+
+        // a byte count reaching past the longest frame is an error, not cut short
+        let mut buf = BytesMut::from(&[0x01, 0x17, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x7F, 0xFE][..]);
+        assert_eq!(Framing::Rtu.decode(&mut buf), Some(Err(FrameError::Oversized(267))));
+        assert!(buf.is_empty());
+
+        // and so is an unknown function code running on without a silent interval
+        let mut buf = BytesMut::from(&[0x41; MAX_RTU_LEN + 1][..]);
+        assert_eq!(Framing::Rtu.decode(&mut buf), Some(Err(FrameError::Oversized(MAX_RTU_LEN + 1))));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_ascii() -> Result<(), Error> {
+        let frame = b":010300000002FA\r\n";
+        assert_eq!(lrc(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x02]), 0xFA);
+        assert_eq!(encode_ascii(1, &[0x03, 0x00, 0x00, 0x00, 0x02]), frame);
+
+        let mut buf = BytesMut::from(&b"\r\n:0103"[..]);
+        assert_eq!(Framing::Ascii.decode(&mut buf), None);
+        buf.extend_from_slice(&frame[5..]);
+        assert_eq!(Framing::Ascii.decode(&mut buf), Some(Ok((1, Bytes::from_static(&[0x03, 0x00, 0x00, 0x00, 0x02])))));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b":010300000002FB\r\n:01\r\n:01030000000ZFA\r\n"[..]);
+        assert_eq!(Framing::Ascii.decode(&mut buf), Some(Err(FrameError::Lrc { expected: 0xFA, actual: 0xFB })));
+        assert_eq!(Framing::Ascii.decode(&mut buf), Some(Err(FrameError::Malformed)));
+        assert_eq!(Framing::Ascii.decode(&mut buf), Some(Err(FrameError::Malformed)));
+        assert_eq!(Framing::Ascii.decode(&mut buf), None);
+
+        let mut buf = BytesMut::from(&b":0103"[..]);
+        assert_eq!(Framing::Ascii.decode(&mut buf), None);
+        assert_eq!(Framing::Ascii.flush(&mut buf), Some(Err(FrameError::Malformed)));
+        assert!(buf.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,368 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use log::{debug, warn};
+use tokio::{
+    sync::Mutex,
+    time::{timeout_at, Instant},
+};
+use tokio_modbus::{
+    client::{tcp, Client, Context},
+    slave::SlaveContext,
+    ExceptionCode, Request, Response, Slave, SlaveRequest,
+};
+
+use crate::{addressing::Addressing, register_manager::RegisterType};
+
+/// Longest an upstream server may take to connect and respond, unless the route sets its own
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The requests a route applies to, and the upstream server they are forwarded to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    /// Any unit if not given
+    pub unit: Option<u8>,
+    /// First and last register number of the range, any address if not given
+    pub range: Option<(u32, u32)>,
+    pub upstream: SocketAddr,
+    pub timeout: Duration,
+}
+
+impl Route {
+    /// Requests for a range only match if every register they touch is within it
+    fn matches(&self, addressing: Addressing, req: &SlaveRequest) -> bool {
+        if self.unit.is_some_and(|unit| unit != req.slave) {
+            return false;
+        }
+
+        let Some((first, last)) = self.range else {
+            return true;
+        };
+
+        let spans = spans(&req.request);
+        !spans.is_empty()
+            && spans.iter().all(|(registers_type, addr, cnt)| {
+                addressing.offset(registers_type, *addr, *cnt).is_ok_and(|offset| {
+                    let start = registers_type.first() + offset as u32;
+                    *cnt > 0 && first <= start && start + *cnt as u32 - 1 <= last
+                })
+            })
+    }
+}
+
+/// The table, address and count of the registers a request touches
+fn spans(req: &Request) -> Vec<(RegisterType, u16, u16)> {
+    match req {
+        Request::ReadCoils(addr, cnt) => vec![(RegisterType::Coils, *addr, *cnt)],
+        Request::WriteSingleCoil(addr, _) => vec![(RegisterType::Coils, *addr, 1)],
+        Request::WriteMultipleCoils(addr, coils) => vec![(RegisterType::Coils, *addr, coils.len() as u16)],
+        Request::ReadDiscreteInputs(addr, cnt) => vec![(RegisterType::Inputs, *addr, *cnt)],
+        Request::ReadInputRegisters(addr, cnt) => vec![(RegisterType::InputRegisters, *addr, *cnt)],
+        Request::ReadHoldingRegisters(addr, cnt) => vec![(RegisterType::HoldingRegisters, *addr, *cnt)],
+        Request::WriteSingleRegister(addr, _) | Request::MaskWriteRegister(addr, _, _) => {
+            vec![(RegisterType::HoldingRegisters, *addr, 1)]
+        }
+        Request::WriteMultipleRegisters(addr, values) => {
+            vec![(RegisterType::HoldingRegisters, *addr, values.len() as u16)]
+        }
+        Request::ReadWriteMultipleRegisters(read_addr, cnt, write_addr, values) => vec![
+            (RegisterType::HoldingRegisters, *read_addr, *cnt),
+            (RegisterType::HoldingRegisters, *write_addr, values.len() as u16),
+        ],
+        _ => vec![],
+    }
+}
+
+/// The request with its addresses translated to the 0-based offsets an upstream server expects
+fn to_offsets(addressing: Addressing, req: &Request<'static>) -> Result<Request<'static>, ExceptionCode> {
+    let offset = |registers_type, addr, cnt| {
+        addressing.offset(&registers_type, addr, cnt).map_err(|_| ExceptionCode::IllegalDataAddress)
+    };
+
+    Ok(match req.clone() {
+        Request::ReadCoils(addr, cnt) => Request::ReadCoils(offset(RegisterType::Coils, addr, cnt)?, cnt),
+        Request::WriteSingleCoil(addr, value) => Request::WriteSingleCoil(offset(RegisterType::Coils, addr, 1)?, value),
+        Request::WriteMultipleCoils(addr, coils) => {
+            Request::WriteMultipleCoils(offset(RegisterType::Coils, addr, coils.len() as u16)?, coils)
+        }
+        Request::ReadDiscreteInputs(addr, cnt) => Request::ReadDiscreteInputs(offset(RegisterType::Inputs, addr, cnt)?, cnt),
+        Request::ReadInputRegisters(addr, cnt) => {
+            Request::ReadInputRegisters(offset(RegisterType::InputRegisters, addr, cnt)?, cnt)
+        }
+        Request::ReadHoldingRegisters(addr, cnt) => {
+            Request::ReadHoldingRegisters(offset(RegisterType::HoldingRegisters, addr, cnt)?, cnt)
+        }
+        Request::WriteSingleRegister(addr, value) => {
+            Request::WriteSingleRegister(offset(RegisterType::HoldingRegisters, addr, 1)?, value)
+        }
+        Request::MaskWriteRegister(addr, and_mask, or_mask) => {
+            Request::MaskWriteRegister(offset(RegisterType::HoldingRegisters, addr, 1)?, and_mask, or_mask)
+        }
+        Request::WriteMultipleRegisters(addr, values) => {
+            Request::WriteMultipleRegisters(offset(RegisterType::HoldingRegisters, addr, values.len() as u16)?, values)
+        }
+        Request::ReadWriteMultipleRegisters(read_addr, cnt, write_addr, values) => Request::ReadWriteMultipleRegisters(
+            offset(RegisterType::HoldingRegisters, read_addr, cnt)?,
+            cnt,
+            offset(RegisterType::HoldingRegisters, write_addr, values.len() as u16)?,
+            values,
+        ),
+        request => request,
+    })
+}
+
+/// The response of an upstream server with the addresses it echoes put back as the client sent them
+fn from_offsets(req: &Request, response: Response) -> Response {
+    match (req, response) {
+        (Request::WriteSingleCoil(addr, _), Response::WriteSingleCoil(_, value)) => Response::WriteSingleCoil(*addr, value),
+        (Request::WriteMultipleCoils(addr, _), Response::WriteMultipleCoils(_, cnt)) => {
+            Response::WriteMultipleCoils(*addr, cnt)
+        }
+        (Request::WriteSingleRegister(addr, _), Response::WriteSingleRegister(_, value)) => {
+            Response::WriteSingleRegister(*addr, value)
+        }
+        (Request::MaskWriteRegister(addr, _, _), Response::MaskWriteRegister(_, and_mask, or_mask)) => {
+            Response::MaskWriteRegister(*addr, and_mask, or_mask)
+        }
+        (Request::WriteMultipleRegisters(addr, _), Response::WriteMultipleRegisters(_, cnt)) => {
+            Response::WriteMultipleRegisters(*addr, cnt)
+        }
+        (_, response) => response,
+    }
+}
+
+/// An upstream server and the connection to it, shared by every client
+pub struct Upstream {
+    addr: SocketAddr,
+    timeout: Duration,
+    connection: Mutex<Option<Context>>,
+}
+
+impl Upstream {
+    /// Connects on the first request, which must be answered within `timeout`
+    pub fn new(addr: SocketAddr, timeout: Duration) -> Self {
+        Upstream {
+            addr,
+            timeout,
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Connects first if there is no connection yet, and drops the connection if the
+    /// upstream server fails, so the next request reconnects
+    pub async fn forward(self: Arc<Self>, req: SlaveRequest<'static>) -> Result<Option<Response>, ExceptionCode> {
+        let upstream = self.addr;
+        let mut connection = self.connection.lock().await;
+        let deadline = Instant::now() + self.timeout;
+
+        let mut context = match connection.take() {
+            Some(context) => context,
+            None => match timeout_at(deadline, tcp::connect(upstream)).await {
+                Ok(Ok(context)) => context,
+                Ok(Err(e)) => {
+                    warn!("Upstream {} unavailable: {}", upstream, e);
+                    return Err(ExceptionCode::GatewayPathUnavailable);
+                }
+                Err(_) => {
+                    warn!("Connecting to upstream {} timed out", upstream);
+                    return Err(ExceptionCode::GatewayPathUnavailable);
+                }
+            },
+        };
+
+        debug!("Forwarding {:?} for unit {} to {}", req.request, req.slave, upstream);
+        context.set_slave(Slave(req.slave));
+
+        match timeout_at(deadline, context.call(req.request)).await {
+            Ok(Ok(response)) => {
+                *connection = Some(context);
+                response.map(Some)
+            }
+            Ok(Err(e)) => {
+                warn!("Upstream {} failed: {}", upstream, e);
+                Err(ExceptionCode::GatewayTargetDevice)
+            }
+            Err(_) => {
+                warn!("Upstream {} did not respond in {:?}", upstream, self.timeout);
+                Err(ExceptionCode::GatewayTargetDevice)
+            }
+        }
+    }
+
+    /// Forwards the request of a client, whose addresses follow `addressing`, to an upstream
+    /// server addressed by 0-based offsets
+    pub async fn relay(
+        self: Arc<Self>,
+        addressing: Addressing,
+        req: SlaveRequest<'static>,
+    ) -> Result<Option<Response>, ExceptionCode> {
+        let request = to_offsets(addressing, &req.request)?;
+        let response = self.forward(SlaveRequest { slave: req.slave, request }).await?;
+
+        Ok(response.map(|response| from_offsets(&req.request, response)))
+    }
+}
+
+/// Routes requests without a register map of their own to upstream servers
+pub struct Gateway {
+    routes: Vec<(Route, Arc<Upstream>)>,
+}
+
+impl Gateway {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Gateway {
+            routes: routes
+                .into_iter()
+                .map(|route| {
+                    let upstream = Arc::new(Upstream::new(route.upstream, route.timeout));
+                    (route, upstream)
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether any route may forward requests for `unit`
+    pub fn routes_unit(&self, unit: u8) -> bool {
+        self.routes.iter().any(|(route, _)| route.unit.is_none_or(|u| u == unit))
+    }
+
+    /// The first route matching the request, in the order they were given
+    pub fn route(&self, addressing: Addressing, req: &SlaveRequest) -> Option<Arc<Upstream>> {
+        self.routes
+            .iter()
+            .find(|(route, _)| route.matches(addressing, req))
+            .map(|(_, upstream)| upstream.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        register_manager::RegisterManager,
+        units::{UnitMap, UnitService, UnknownUnit},
+        validation::parse_route,
+    };
+    use serde_json::json;
+    use std::collections::HashMap;
+    use tokio::{net::TcpListener, test};
+    use tokio_modbus::server::{
+        tcp::{accept_tcp_connection, Server},
+        Service,
+    };
+    type Error = Box<dyn std::error::Error>;
+
+    fn request(slave: u8, request: Request<'static>) -> SlaveRequest<'static> {
+        SlaveRequest { slave, request }
+    }
+
+    #[test]
+    pub async fn gateway_test() -> Result<(), Error> {
+        assert_eq!(
+            parse_route("5:400101-400200=127.0.0.1:502@250ms")?,
+            Route {
+                unit: Some(5),
+                range: Some((400101, 400200)),
+                upstream: "127.0.0.1:502".parse()?,
+                timeout: Duration::from_millis(250),
+            }
+        );
+        assert_eq!(parse_route("300001-300010=127.0.0.1:502")?.unit, None);
+        assert_eq!(parse_route("7=127.0.0.1:502")?.timeout, DEFAULT_TIMEOUT);
+        assert!(parse_route("300001-400010=127.0.0.1:502").is_err());
+        assert!(parse_route("400010-400001=127.0.0.1:502").is_err());
+        assert!(parse_route("5=localhost").is_err());
+
+        // an upstream server with unit 5
+        let upstream_map = Arc::new(UnitMap::new(
+            HashMap::from([(5, Arc::new(RegisterManager::from_json(json!({ "400101": 3 })).unwrap()))]),
+            None,
+            UnknownUnit::Exception,
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = listener.local_addr()?;
+        tokio::spawn(async move {
+            let new_service = |addr| Ok(Some(UnitService::new(&upstream_map, addr, None, None, Addressing::ZeroBased)));
+            let on_connected = |stream, addr| async move { accept_tcp_connection(stream, addr, &new_service) };
+            Server::new(listener).serve(&on_connected, |_| {}).await
+        });
+
+        // nothing listens on a closed port, and a listener that never accepts never answers
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let silent = TcpListener::bind("127.0.0.1:0").await?;
+
+        let route = |unit, range, upstream, timeout| Route { unit, range, upstream, timeout };
+        let range = route(None, Some((300001, 300010)), closed, DEFAULT_TIMEOUT);
+        let gateway = Gateway::new(vec![
+            route(Some(5), None, upstream, DEFAULT_TIMEOUT),
+            range.clone(),
+            route(Some(7), None, silent.local_addr()?, Duration::from_millis(50)),
+        ]);
+
+        let local = Arc::new(RegisterManager::from_json(json!({ "400001": 1 })).unwrap());
+        let unit_map = UnitMap::new(HashMap::from([(1, local)]), None, UnknownUnit::Exception).with_gateway(gateway);
+        let service = UnitService::new(&unit_map, "127.0.0.1:1".parse()?, None, None, Addressing::ZeroBased);
+
+        assert_eq!(
+            service.call(request(5, Request::ReadHoldingRegisters(100, 1))).await,
+            Ok(Some(Response::ReadHoldingRegisters(vec![3])))
+        );
+        // exceptions of the upstream server are passed on
+        assert_eq!(
+            service.call(request(5, Request::ReadHoldingRegisters(1000, 1))).await,
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            service.call(request(1, Request::ReadHoldingRegisters(0, 1))).await,
+            Ok(Some(Response::ReadHoldingRegisters(vec![1])))
+        );
+
+        assert_eq!(
+            service.call(request(9, Request::ReadInputRegisters(0, 10))).await,
+            Err(ExceptionCode::GatewayPathUnavailable)
+        );
+        assert_eq!(
+            service.call(request(7, Request::ReadHoldingRegisters(0, 1))).await,
+            Err(ExceptionCode::GatewayTargetDevice)
+        );
+
+        // requests reaching past the range are left to the unknown unit policy
+        assert!(!range.matches(Addressing::ZeroBased, &request(9, Request::ReadInputRegisters(5, 10))));
+        assert!(range.matches(Addressing::ZeroBased, &request(9, Request::ReadInputRegisters(5, 5))));
+        assert!(range.matches(Addressing::Legacy, &request(9, Request::ReadInputRegisters(30001, 10))));
+        assert!(!range.matches(Addressing::ZeroBased, &request(9, Request::ReadHoldingRegisters(0, 1))));
+        assert_eq!(
+            service.call(request(9, Request::ReadInputRegisters(5, 10))).await,
+            Err(ExceptionCode::GatewayTargetDevice)
+        );
+
+        // upstream servers are addressed by offsets, whatever the addressing of the client
+        let legacy = UnitService::new(&unit_map, "127.0.0.1:1".parse()?, None, None, Addressing::Legacy);
+        assert_eq!(
+            legacy.call(request(5, Request::ReadHoldingRegisters(40101, 1))).await,
+            Ok(Some(Response::ReadHoldingRegisters(vec![3])))
+        );
+        assert!(matches!(
+            legacy.call(request(5, Request::WriteSingleRegister(40101, 4))).await,
+            Ok(Some(Response::WriteSingleRegister(40101, _)))
+        ));
+        assert_eq!(
+            service.call(request(5, Request::ReadHoldingRegisters(100, 1))).await,
+            Ok(Some(Response::ReadHoldingRegisters(vec![4])))
+        );
+        assert_eq!(
+            legacy.call(request(5, Request::ReadHoldingRegisters(100, 1))).await,
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+
+        // forwarded requests are subject to the whitelists too
+        let whitelist = Some(vec!["10.0.0.0/8".parse()?]);
+        let service = UnitService::new(&unit_map, "127.0.0.1:1".parse()?, None, whitelist, Addressing::ZeroBased);
+        assert_eq!(
+            service.call(request(5, Request::WriteSingleRegister(100, 1))).await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+
+        Ok(())
+    }
+
+}
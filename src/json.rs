@@ -1,9 +1,14 @@
-use crate::pack::{PackFormat, PackType};
+use crate::addressing::Addressing;
+use crate::register_manager::RegisterType;
+use crate::pack::{ByteField, ByteOrder, PackFormat, PackType, StringPadding};
+use half::f16;
+use log::warn;
 use serde_json::{Map, Value};
 use std::{
     collections::HashMap,
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     io::{ErrorKind, Read, Write},
+    path::Path,
     str::FromStr,
 };
 
@@ -22,13 +27,37 @@ impl std::fmt::Display for JsonError {
         match self {
             JsonError::Invalid(msg) => f.write_str(msg),
             JsonError::Other(msg) => f.write_str(msg),
-            JsonError::Io(err) => f.write_str(format!("{}", err.to_string()).as_str()),
+            JsonError::Io(err) => f.write_str(format!("{}", err).as_str()),
             JsonError::NoFile => f.write_str("No file"),
         }
     }
 }
 
+/// Backups of a register file kept by default, see `write`
+pub const DEFAULT_BACKUPS: usize = 3;
+
+/// Loads a register file, falling back to its newest valid backup if the file is corrupt
 pub fn load(path: &str) -> Result<Value, JsonError> {
+    let error = match read(path) {
+        Ok(data) => return Ok(data),
+        Err(JsonError::NoFile) => return Err(JsonError::NoFile),
+        Err(e) => e,
+    };
+
+    for backup in (1..).map(|i| backup_path(path, i)).take_while(|b| Path::new(b).exists()) {
+        match read(&backup) {
+            Ok(data) => {
+                warn!("{} is corrupt ({}), loaded {} instead", path, error, backup);
+                return Ok(data);
+            }
+            Err(e) => warn!("Backup {} is corrupt too: {}", backup, e),
+        }
+    }
+
+    Err(error)
+}
+
+fn read(path: &str) -> Result<Value, JsonError> {
     let mut file = match File::open(path) {
         Ok(v) => v,
         Err(e) => {
@@ -44,166 +73,981 @@ pub fn load(path: &str) -> Result<Value, JsonError> {
         .map_err(|e| JsonError::Other(e.to_string()))?;
 
     let data: Value =
-        serde_json::from_str(&content.as_str()).map_err(|e| JsonError::Other(e.to_string()))?;
+        serde_json::from_str(content.as_str()).map_err(|e| JsonError::Other(e.to_string()))?;
 
     Ok(data)
 }
 
-pub fn write(value: serde_json::Value, path: &str) -> Result<(), JsonError> {
+/// Path of the `n`th newest backup of a register file, e.g. `data.json.1`
+pub fn backup_path(path: &str, n: usize) -> String {
+    format!("{}.{}", path, n)
+}
+
+/// Replaces a register file so that a crash at any point leaves either the old or the new
+/// contents. The new contents go to a temporary file that is synced and renamed over the
+/// original, after the original has become the newest of `backups` rotated backups.
+pub fn write(value: serde_json::Value, path: &str, backups: usize) -> Result<(), JsonError> {
+    let string = serde_json::to_string_pretty(&value)
+        .map_err(|_| JsonError::Other("Error converting to string".into()))?;
+
+    let temp = format!("{}.tmp", path);
     let mut file = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(path)
-        .map_err(|e| JsonError::Io(e))?;
+        .open(&temp)
+        .map_err(JsonError::Io)?;
 
-    let string = serde_json::to_string_pretty(&value)
-        .map_err(|_| JsonError::Other("Error converting to string".into()))?;
+    file.write_all(string.as_bytes()).map_err(JsonError::Io)?;
+    file.sync_all().map_err(JsonError::Io)?;
 
-    file.write(string.as_bytes())
-        .map_err(|e| JsonError::Io(e))?;
+    if backups > 0 && Path::new(path).exists() {
+        rotate(path, backups).map_err(JsonError::Io)?;
+    }
 
-    file.flush().map_err(|e| JsonError::Io(e))?;
+    fs::rename(&temp, path).map_err(JsonError::Io)?;
+    sync_dir(path).map_err(JsonError::Io)?;
 
     Ok(())
 }
 
-pub fn parse(data: Value) -> Result<(HashMap<u16, u16>, Vec<String>), JsonError> {
-    if let Value::Object(ref map) = data {
-        let keys: Vec<String> = map.keys().cloned().collect();
-        let mut registers: HashMap<u16, u16> = HashMap::new(); // #TODO! measure length in advance
+/// Shifts the backups up by one, dropping the oldest, and keeps the current file as the newest
+fn rotate(path: &str, backups: usize) -> std::io::Result<()> {
+    for n in (1..backups).rev() {
+        match fs::rename(backup_path(path, n), backup_path(path, n + 1)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    let newest = backup_path(path, 1);
+    match fs::remove_file(&newest) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
 
-        for (k, v) in map {
-            let PackFormat { address, pack_type } = PackFormat::parse(k)
+    // a link costs no writes, but not every file system supports them
+    fs::hard_link(path, &newest).or_else(|_| fs::copy(path, &newest).map(|_| ()))
+}
+
+/// Makes the renames in the directory of `path` durable
+#[cfg(unix)]
+fn sync_dir(path: &str) -> std::io::Result<()> {
+    let dir = Path::new(path).parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// The original register definitions, kept to write registers back in the same form
+pub type Layout = Map<String, Value>;
+
+/// Top-level key setting the default byte order of every register key
+/// without an explicit order
+pub const BYTE_ORDER_KEY: &str = "byte_order";
+
+/// Top-level key setting the filler of string registers, either "nul" or "space"
+pub const STRING_PADDING_KEY: &str = "string_padding";
+
+/// Top-level key holding the objects answered to Read Device Identification
+pub const DEVICE_ID_KEY: &str = "device_identification";
+
+/// Top-level key setting the ID answered to Report Server ID
+pub const SERVER_ID_KEY: &str = "server_id";
+
+/// Top-level key setting the addressing mode of the file, overriding that of the listener
+pub const ADDRESSING_KEY: &str = "addressing";
+
+/// Top-level key holding the blocks polled from a remote device into the file's registers
+pub const POLL_KEY: &str = "poll";
+
+/// Server ID used when the file doesn't set one
+pub const DEFAULT_SERVER_ID: u8 = 1;
+
+/// Returns true for top-level keys that configure the file rather than
+/// define a register
+fn is_setting(key: &str) -> bool {
+    matches!(key, BYTE_ORDER_KEY | STRING_PADDING_KEY | DEVICE_ID_KEY | SERVER_ID_KEY | ADDRESSING_KEY | POLL_KEY)
+}
+
+/// File-wide defaults for register keys
+#[derive(Debug, Default, Clone, Copy)]
+struct Settings {
+    order: ByteOrder,
+    padding: StringPadding,
+}
+
+impl Settings {
+    fn from_layout(map: &Layout) -> Result<Self, JsonError> {
+        fn setting<T>(map: &Layout, key: &str, parse: fn(&str) -> Option<T>) -> Result<Option<T>, JsonError> {
+            map.get(key)
+                .map(|v| v.as_str().and_then(parse).ok_or_else(|| {
+                    JsonError::Invalid(format!("Invalid value for '{}'", key))
+                }))
+                .transpose()
+        }
+
+        Ok(Settings {
+            order: setting(map, BYTE_ORDER_KEY, ByteOrder::parse)?.unwrap_or_default(),
+            padding: setting(map, STRING_PADDING_KEY, StringPadding::parse)?.unwrap_or_default(),
+        })
+    }
+}
+
+/// Reads the `server_id` setting, a number from 0 to 255
+pub fn server_id(map: &Layout) -> Result<u8, JsonError> {
+    map.get(SERVER_ID_KEY)
+        .map(|v| {
+            v.as_u64()
+                .and_then(|id| u8::try_from(id).ok())
+                .ok_or_else(|| JsonError::Invalid(format!("Invalid value for '{}'", SERVER_ID_KEY)))
+        })
+        .unwrap_or(Ok(DEFAULT_SERVER_ID))
+}
+
+/// Reads the `addressing` setting, if present
+pub fn addressing(map: &Layout) -> Result<Option<Addressing>, JsonError> {
+    map.get(ADDRESSING_KEY)
+        .map(|v| {
+            v.as_str()
+                .and_then(Addressing::parse)
+                .ok_or_else(|| JsonError::Invalid(format!("Invalid value for '{}'", ADDRESSING_KEY)))
+        })
+        .transpose()
+}
+
+/// Access mode of a register as declared in its definition
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Access {
+    #[default]
+    ReadWrite,
+    ReadOnly,
+    /// Writable once, after which it is read-only until the server restarts
+    WriteOnce,
+}
+
+impl Access {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "rw" => Some(Access::ReadWrite),
+            "ro" => Some(Access::ReadOnly),
+            "once" => Some(Access::WriteOnce),
+            _ => None,
+        }
+    }
+}
+
+/// Descriptive fields of an object-form definition
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Metadata {
+    pub key: String,
+    /// First register of the key in 6-digit notation
+    pub address: u32,
+    /// Number of registers spanned by the key
+    pub len: u32,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub unit: Option<String>,
+    pub access: Access,
+    /// Type and byte order of every value held by the key, used to check `min` and `max`
+    pub pack_type: PackType,
+    pub order: ByteOrder,
+    /// Bounds of the raw typed values, before any scaling
+    pub min: Option<serde_json::Number>,
+    pub max: Option<serde_json::Number>,
+    /// Mask of the named bits of a bitfield, the only ones persisted and so the only ones that may be set
+    pub bits: Option<u64>,
+}
+
+impl Metadata {
+    fn from_definition(key: &str, format: &PackFormat, definition: &Map<String, Value>, settings: &Settings) -> Result<Self, String> {
+        let text = |field: &str| -> Result<Option<String>, String> {
+            definition
+                .get(field)
+                .map(|v| v.as_str().map(str::to_string).ok_or_else(|| format!("'{}' should be a string", field)))
+                .transpose()
+        };
+
+        let bound = |field: &str| -> Result<Option<serde_json::Number>, String> {
+            match definition.get(field) {
+                None => Ok(None),
+                Some(_) if matches!(format.pack_type, PackType::String(_) | PackType::Bytes(_)) => {
+                    Err(format!("'{}' only applies to numbers", field))
+                }
+                Some(v) => v.as_number().cloned().map(Some).ok_or_else(|| format!("'{}' should be a number", field)),
+            }
+        };
+
+        Ok(Metadata {
+            key: key.to_string(),
+            address: format.address,
+            len: format.len() as u32,
+            name: text("name")?,
+            description: text("description")?,
+            unit: text("unit")?,
+            access: text("access")?
+                .map(|a| Access::parse(&a).ok_or_else(|| format!("Invalid access '{}'", a)))
+                .transpose()?
+                .unwrap_or_default(),
+            pack_type: format.pack_type.clone(),
+            order: format.order.unwrap_or(settings.order),
+            min: bound("min")?,
+            max: bound("max")?,
+            bits: match definition.get("type").and_then(Value::as_str) {
+                Some(BITS_TYPE) => Some(bit_mask(&bit_names(definition, format)?)),
+                _ => None,
+            },
+        })
+    }
+
+    /// Whether the register at `address`, in 6-digit notation, belongs to this key
+    pub fn contains(&self, address: u32) -> bool {
+        (self.address..self.address + self.len).contains(&address)
+    }
+
+    /// Whether every typed value held by `words`, all registers of the key,
+    /// is within `min` and `max`
+    pub fn in_range(&self, words: &[u16]) -> bool {
+        if self.min.is_none() && self.max.is_none() {
+            return true;
+        }
+
+        words.chunks(self.pack_type.len()).all(|chunk| {
+            decode_value(chunk, &self.pack_type, self.order)
+                .is_ok_and(|v| within(&v, self.min.as_ref(), self.max.as_ref()))
+        })
+    }
+}
+
+impl Metadata {
+    /// Whether `words`, all registers of the key, only set named bits if the key is a bitfield
+    pub fn named_bits_only(&self, words: &[u16]) -> bool {
+        let Some(mask) = self.bits else {
+            return true;
+        };
+
+        decode_value(words, &self.pack_type, self.order)
+            .ok()
+            .and_then(|v| v.as_u64())
+            .is_some_and(|word| word & !mask == 0)
+    }
+}
+
+/// Compares a decoded number to optional bounds, as integers when all of them
+/// are integers so that 64-bit values keep their precision. NaN is never within bounds.
+fn within(value: &Value, min: Option<&serde_json::Number>, max: Option<&serde_json::Number>) -> bool {
+    let Some(value) = value.as_number() else {
+        return false;
+    };
+
+    if !value.is_f64() && min.is_none_or(|n| !n.is_f64()) && max.is_none_or(|n| !n.is_f64()) {
+        let v = value.as_i128();
+        min.is_none_or(|n| v >= n.as_i128()) && max.is_none_or(|n| v <= n.as_i128())
+    } else {
+        value.as_f64().is_some_and(|v| {
+            min.and_then(|n| n.as_f64()).is_none_or(|n| v >= n)
+                && max.and_then(|n| n.as_f64()).is_none_or(|n| v <= n)
+        })
+    }
+}
+
+impl std::fmt::Display for Metadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.key)?;
+
+        if let Some(name) = &self.name {
+            write!(f, " '{}'", name)?;
+        }
+
+        if let Some(unit) = &self.unit {
+            write!(f, " [{}]", unit)?;
+        }
+
+        match self.access {
+            Access::ReadOnly => f.write_str(" (read-only)")?,
+            Access::WriteOnce => f.write_str(" (write-once)")?,
+            Access::ReadWrite => {}
+        }
+
+        if let Some(description) = &self.description {
+            write!(f, ": {}", description)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct JsonResult {
+    /// Register values by their number in 6-digit notation
+    pub registers: HashMap<u32, u16>,
+    /// The original object, used as the layout for `registers_to_object`
+    pub layout: Layout,
+    /// Metadata of every object-form definition
+    pub metadata: Vec<Metadata>,
+}
+
+/// Parses the register definitions
+pub fn parse(data: Value) -> Result<JsonResult, JsonError> {
+    if let Value::Object(map) = data {
+        let mut registers: HashMap<u32, u16> = HashMap::new(); // #TODO! measure length in advance
+        let mut metadata: Vec<Metadata> = Vec::new();
+        let settings = Settings::from_layout(&map)?;
+
+        for (k, v) in map.iter().filter(|(k, _)| !is_setting(k)) {
+            let format = PackFormat::parse(k)
                 .map_err(|_| JsonError::Invalid(format!("Error parsing key '{}'", k)))?;
+            let address = format.address;
+
+            let meta = match v {
+                Value::Object(definition) => Some(
+                    Metadata::from_definition(k, &format, definition, &settings)
+                        .map_err(|e| JsonError::Invalid(format!("Error in definition of key '{}': {}", k, e)))?,
+                ),
+                _ => None,
+            };
+
+            if let Some(name) = meta.as_ref().and_then(|m| m.name.as_ref()).filter(|&n| metadata.iter().any(|m| m.name.as_ref() == Some(n))) {
+                return Err(JsonError::Invalid(format!("Name '{}' is used more than once", name)));
+            }
+
+            let table = RegisterType::locate(address).map(|(table, _)| table);
 
-            let number = match v {
-                Value::Number(n) => n,
-                _ => {
+            let bytes: Vec<u16> = match table {
+                Some(RegisterType::Coils | RegisterType::Inputs) => {
+                    let v = &resolve_value(v, &format)
+                        .map_err(|e| JsonError::Invalid(format!("Error in definition of key '{}': {}", k, e)))?;
+
+                    if !matches!(format.pack_type, PackType::U16 | PackType::I16) {
+                        return Err(JsonError::Invalid(format!(
+                            "Key '{}' should have a 16-bit integer type",
+                            k
+                        )));
+                    }
+
+                    let bits = match format.count {
+                        Some(count) => v
+                            .as_array()
+                            .filter(|a| a.len() == count)
+                            .map(|a| a.iter().collect())
+                            .ok_or_else(|| {
+                                JsonError::Invalid(format!("Key '{}' should be an array of {} bits", k, count))
+                            })?,
+                        None => vec![v],
+                    };
+
+                    bits.into_iter()
+                        .map(|bit| {
+                            bit.as_i64()
+                                .filter(|&n| n == 0 || n == 1)
+                                .map(|n| n as u16)
+                                .ok_or_else(|| {
+                                    JsonError::Invalid(format!("Key '{}' should be 0 or 1", k))
+                                })
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                Some(RegisterType::InputRegisters | RegisterType::HoldingRegisters) => {
+                    resolve_value(v, &format).and_then(|v| encode_key(&v, &format, &settings)).map_err(|e| {
+                        JsonError::Invalid(format!(
+                            "Error converting key {} to type {:?}: {}",
+                            address, format.pack_type, e
+                        ))
+                    })?
+                }
+                None => {
                     return Err(JsonError::Invalid(format!(
-                        "Key '{}' should be a number",
+                        "Key {} is outside modbus range",
                         k
                     )))
                 }
             };
 
-            match address {
-                1..=9999 | 10001..=19999 => {
-                    let bit = number
-                        .as_i64()
-                        .filter(|&n| n == 0 || n == 1)
-                        .ok_or_else(|| {
-                            JsonError::Invalid(format!("Key '{}' should be 0 or 1", k))
-                        })? as u16;
+            let table_end = table.map(|t| t.first() + u16::MAX as u32).unwrap_or_default();
 
-                    registers.insert(address, bit);
-                }
-                30001..=39999 | 40001..=49999 => {
+            if address as usize + bytes.len() - 1 > table_end as usize {
+                return Err(JsonError::Invalid(format!(
+                    "Key '{}' runs past the end of its register range",
+                    k
+                )));
+            }
 
-                    // need i128 as an intermediate representation to support both i64 and u64
-                    let transformed = number.as_i128().ok_or(JsonError::Invalid(format!(
-                        "Error parsing number at key.  '{}'",
+            for (idx, byte) in bytes.iter().enumerate() {
+                if registers.insert(address + idx as u32, *byte).is_some() {
+                    return Err(JsonError::Invalid(format!(
+                        "Overwrote register at key '{}'",
                         address
-                    )))?;
-                    
-                    let bytes: Vec<u16> = Ok(transformed)
-                        .and_then(|n| match pack_type {
-                            PackType::I16 => i16::try_from(n).map(|v| v.to_be_bytes().to_vec()),
-                            PackType::U16 => u16::try_from(n).map(|v| v.to_be_bytes().to_vec()),
-                            PackType::I32 => i32::try_from(n).map(|v| v.to_be_bytes().to_vec()),
-                            PackType::U32 => u32::try_from(n).map(|v| v.to_be_bytes().to_vec()),
-                            PackType::I64 => i64::try_from(n).map(|v| v.to_be_bytes().to_vec()),
-                            PackType::U64 => u64::try_from(n).map(|v| v.to_be_bytes().to_vec()),
-                        })
-                        .map(|vec| {
-                            vec.chunks(2)
-                                .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
-                                .collect()
-                        })
-                        .map_err(|e| JsonError::Invalid(format!(
-                            "Error converting key {} to type {:?}: {}",
-                            address, pack_type, e
-                        )))?;
-
-                    for (idx, byte) in bytes.iter().enumerate() {
-                        if registers.insert(address + idx as u16, *byte).is_some() {
-                            return Err(JsonError::Invalid(format!(
-                                "Overwrote register at key '{}'",
-                                address
-                            )));
+                    )));
+                }
+            }
+
+            if let Some(meta) = meta {
+                if !meta.in_range(&bytes) {
+                    return Err(JsonError::Invalid(format!(
+                        "Value of key '{}' is outside its min/max",
+                        k
+                    )));
+                }
+
+                metadata.push(meta);
+            }
+        }
+
+        Ok(JsonResult { registers, layout: map, metadata })
+    } else {
+        Err(JsonError::Invalid("data is not an object".into()))
+    }
+}
+
+const NAN: &str = "NaN";
+const INFINITY: &str = "Infinity";
+const NEG_INFINITY: &str = "-Infinity";
+
+/// Reads a float from a json value, accepting the strings "NaN", "Infinity"
+/// and "-Infinity" since json itself has no representation for them
+fn value_as_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => match s.as_str() {
+            NAN => Some(f64::NAN),
+            INFINITY => Some(f64::INFINITY),
+            NEG_INFINITY => Some(f64::NEG_INFINITY),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Writes a float as a json value, see `value_as_f64`
+fn f64_as_value(value: f64, repr: String) -> Result<Value, String> {
+    if value.is_nan() {
+        Ok(Value::String(NAN.into()))
+    } else if value.is_infinite() {
+        Ok(Value::String(if value > 0.0 { INFINITY } else { NEG_INFINITY }.into()))
+    } else {
+        serde_json::Number::from_str(repr.as_str())
+            .map(Value::Number)
+            .map_err(|_| "Error creating serde number value".to_string())
+    }
+}
+
+/// Converts a finite f64 to a narrower float, failing if the value does not fit
+fn narrow_float<T>(value: f64, narrowed: T, widened: f64) -> Result<T, String> {
+    if value.is_finite() && widened.is_infinite() {
+        Err(format!("{} is out of range", value))
+    } else {
+        Ok(narrowed)
+    }
+}
+
+/// Packs an ASCII string two characters per register, padding the remainder.
+/// Only the byte order within each register applies to strings.
+fn encode_string(value: &Value, chars: usize, order: ByteOrder, padding: StringPadding) -> Result<Vec<u16>, String> {
+    let string = value.as_str().ok_or("Value should be a string")?;
+
+    if !string.is_ascii() {
+        return Err("String should only contain ASCII characters".into());
+    }
+
+    if string.len() > chars {
+        return Err(format!("String is longer than {} characters", chars));
+    }
+
+    let mut bytes = string.as_bytes().to_vec();
+    bytes.resize(chars.div_ceil(2) * 2, padding.byte());
+
+    Ok(bytes
+        .chunks(2)
+        .map(|chunk| match order.is_byte_swapped() {
+            true => u16::from_le_bytes([chunk[0], chunk[1]]),
+            false => u16::from_be_bytes([chunk[0], chunk[1]]),
+        })
+        .collect())
+}
+
+/// Unpacks a string packed by `encode_string`, trimming trailing padding.
+/// Non-ASCII bytes written by clients are replaced with '?'.
+fn decode_string(words: &[u16], chars: usize, order: ByteOrder) -> Value {
+    let string: String = words
+        .iter()
+        .flat_map(|word| match order.is_byte_swapped() {
+            true => word.to_le_bytes(),
+            false => word.to_be_bytes(),
+        })
+        .take(chars)
+        .map(|b| if b.is_ascii() { b as char } else { '?' })
+        .collect();
+
+    Value::String(string.trim_end_matches(['\0', ' ']).to_string())
+}
+
+/// Packs byte-wide fields from a json array holding a value for every
+/// non-pad field. The first field goes in the high half of a register unless
+/// the order is byte-swapped.
+fn encode_bytes(value: &Value, fields: &[ByteField], order: ByteOrder) -> Result<Vec<u16>, String> {
+    let expected = fields.iter().filter(|f| **f != ByteField::Pad).count();
+    let mut values = value
+        .as_array()
+        .filter(|a| a.len() == expected)
+        .ok_or_else(|| format!("Value should be an array of {} elements", expected))?
+        .iter();
+
+    let mut bytes = fields
+        .iter()
+        .map(|field| {
+            let value = match field {
+                ByteField::Pad => return Ok(0),
+                _ => values.next().ok_or("Missing value")?,
+            };
+
+            match field {
+                ByteField::Bool => value
+                    .as_bool()
+                    .or_else(|| value.as_u64().filter(|&n| n <= 1).map(|n| n == 1))
+                    .map(|b| b as u8)
+                    .ok_or_else(|| format!("{} should be a boolean", value)),
+                ByteField::I8 => value
+                    .as_i64()
+                    .and_then(|n| i8::try_from(n).ok())
+                    .map(|n| n as u8)
+                    .ok_or_else(|| format!("{} is out of range", value)),
+                _ => value
+                    .as_u64()
+                    .and_then(|n| u8::try_from(n).ok())
+                    .ok_or_else(|| format!("{} is out of range", value)),
+            }
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+
+    bytes.resize(fields.len().div_ceil(2) * 2, 0);
+
+    Ok(bytes
+        .chunks(2)
+        .map(|chunk| match order.is_byte_swapped() {
+            true => u16::from_le_bytes([chunk[0], chunk[1]]),
+            false => u16::from_be_bytes([chunk[0], chunk[1]]),
+        })
+        .collect())
+}
+
+/// Unpacks fields packed by `encode_bytes`, skipping pad bytes
+fn decode_bytes(words: &[u16], fields: &[ByteField], order: ByteOrder) -> Value {
+    let values = words
+        .iter()
+        .flat_map(|word| match order.is_byte_swapped() {
+            true => word.to_le_bytes(),
+            false => word.to_be_bytes(),
+        })
+        .zip(fields)
+        .filter_map(|(byte, field)| match field {
+            ByteField::Pad => None,
+            ByteField::Bool => Some(Value::Bool(byte != 0)),
+            ByteField::I8 => Some(Value::from(byte as i8)),
+            ByteField::U8 => Some(Value::from(byte)),
+        })
+        .collect();
+
+    Value::Array(values)
+}
+
+/// Encodes a json value as register words in the given byte order
+fn encode_value(value: &Value, pack_type: &PackType, order: ByteOrder, padding: StringPadding) -> Result<Vec<u16>, String> {
+    match pack_type {
+        PackType::String(chars) => return encode_string(value, *chars, order, padding),
+        PackType::Bytes(fields) => return encode_bytes(value, fields, order),
+        _ => {}
+    }
+
+    let bytes: Vec<u8> = if pack_type.is_float() {
+        let n = value_as_f64(value).ok_or("Value should be a number")?;
+
+        match pack_type {
+            PackType::F16 => {
+                let v = f16::from_f64(n);
+                narrow_float(n, v, v.to_f64())?.to_be_bytes().to_vec()
+            }
+            PackType::F32 => narrow_float(n, n as f32, n as f32 as f64)?.to_be_bytes().to_vec(),
+            _ => n.to_be_bytes().to_vec(),
+        }
+    } else {
+        // need i128 as an intermediate representation to support both i64 and u64
+        let n = value
+            .as_number()
+            .and_then(|n| n.as_i128())
+            .ok_or("Value should be an integer")?;
+
+        match pack_type {
+            PackType::I16 => i16::try_from(n).map(|v| v.to_be_bytes().to_vec()),
+            PackType::U16 => u16::try_from(n).map(|v| v.to_be_bytes().to_vec()),
+            PackType::I32 => i32::try_from(n).map(|v| v.to_be_bytes().to_vec()),
+            PackType::U32 => u32::try_from(n).map(|v| v.to_be_bytes().to_vec()),
+            PackType::I64 => i64::try_from(n).map(|v| v.to_be_bytes().to_vec()),
+            _ => u64::try_from(n).map(|v| v.to_be_bytes().to_vec()),
+        }
+        .map_err(|e| e.to_string())?
+    };
+
+    let words: Vec<u16> = bytes
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+        .collect();
+
+    Ok(order.apply(&words))
+}
+
+/// Decodes register words in the given byte order into a json value
+fn decode_value(words: &[u16], pack_type: &PackType, order: ByteOrder) -> Result<Value, String> {
+    match pack_type {
+        PackType::String(chars) => return Ok(decode_string(words, *chars, order)),
+        PackType::Bytes(fields) => return Ok(decode_bytes(words, fields, order)),
+        _ => {}
+    }
+
+    let raw = order
+        .apply(words)
+        .iter()
+        .take(pack_type.len())
+        .fold(0u64, |acc, word| (acc << 16) | *word as u64);
+
+    match pack_type {
+        PackType::F16 => {
+            let v = f16::from_bits(raw as u16);
+            f64_as_value(v.to_f64(), format!("{:?}", v.to_f32()))
+        }
+        PackType::F32 => {
+            let v = f32::from_bits(raw as u32);
+            f64_as_value(v as f64, format!("{:?}", v))
+        }
+        PackType::F64 => {
+            let v = f64::from_bits(raw);
+            f64_as_value(v, format!("{:?}", v))
+        }
+        _ => {
+            let number_str = match pack_type {
+                PackType::I16 => (raw as u16 as i16).to_string(),
+                PackType::I32 => (raw as u32 as i32).to_string(),
+                PackType::I64 => (raw as i64).to_string(),
+                _ => raw.to_string(),
+            };
+
+            serde_json::Number::from_str(number_str.as_str())
+                .map(Value::Number)
+                .map_err(|_| "Error creating serde number value".to_string())
+        }
+    }
+}
+
+/// Object-form type of a register holding named bits
+const BITS_TYPE: &str = "bits";
+
+/// Reads the bit names of a bitfield definition, e.g. `{"0": "running"}`
+fn bit_names(definition: &Map<String, Value>, format: &PackFormat) -> Result<Vec<(u32, String)>, String> {
+    if format.count.is_some() || !matches!(format.pack_type, PackType::U16 | PackType::U32 | PackType::U64) {
+        return Err("Bitfields should be a single unsigned integer".into());
+    }
+
+    let width = format.pack_type.len() as u32 * 16;
+    let mut names: Vec<(u32, String)> = Vec::new();
+
+    for (bit, name) in definition.get("bits").and_then(Value::as_object).ok_or("Missing 'bits' object")? {
+        let bit = bit.parse::<u32>().ok().filter(|&b| b < width)
+            .ok_or_else(|| format!("Bit '{}' should be a number below {}", bit, width))?;
+        let name = name.as_str().ok_or_else(|| format!("Name of bit {} should be a string", bit))?;
+
+        if names.iter().any(|(_, n)| n == name) {
+            return Err(format!("Bit name '{}' is used more than once", name));
+        }
+
+        names.push((bit, name.to_string()));
+    }
+
+    Ok(names)
+}
+
+fn bit_mask(names: &[(u32, String)]) -> u64 {
+    names.iter().fold(0, |mask, (bit, _)| mask | 1 << bit)
+}
+
+/// How scaled values are rounded to the nearest raw integer
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rounding {
+    /// Half away from zero
+    Nearest,
+    Floor,
+    Ceil,
+    Truncate,
+}
+
+impl Rounding {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "nearest" => Some(Rounding::Nearest),
+            "floor" => Some(Rounding::Floor),
+            "ceil" => Some(Rounding::Ceil),
+            "truncate" => Some(Rounding::Truncate),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, value: f64) -> f64 {
+        match self {
+            Rounding::Nearest => value.round(),
+            Rounding::Floor => value.floor(),
+            Rounding::Ceil => value.ceil(),
+            Rounding::Truncate => value.trunc(),
+        }
+    }
+}
+
+/// Linear conversion between the engineering value in the json and the raw
+/// register value, `raw = value * scale + offset`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Scaling {
+    scale: f64,
+    offset: f64,
+    rounding: Rounding,
+}
+
+impl Scaling {
+    fn from_definition(definition: &Map<String, Value>) -> Result<Option<Self>, String> {
+        let number = |key: &str| -> Result<Option<f64>, String> {
+            definition
+                .get(key)
+                .map(|v| v.as_f64().filter(|n| n.is_finite()).ok_or_else(|| format!("'{}' should be a number", key)))
+                .transpose()
+        };
+
+        let (scale, offset) = match (number("scale")?, number("offset")?) {
+            (None, None) => return Ok(None),
+            (scale, offset) => (scale.unwrap_or(1.0), offset.unwrap_or(0.0)),
+        };
+
+        if scale == 0.0 {
+            return Err("'scale' should not be zero".into());
+        }
+
+        let rounding = match definition.get("rounding") {
+            None => Rounding::Nearest,
+            Some(v) => v.as_str().and_then(Rounding::parse).ok_or("Invalid 'rounding'")?,
+        };
+
+        Ok(Some(Scaling { scale, offset, rounding }))
+    }
+
+    /// Converts an engineering value, or an array of them, to raw values for the format
+    fn raw(&self, value: &Value, format: &PackFormat) -> Result<Value, String> {
+        if matches!(format.pack_type, PackType::String(_) | PackType::Bytes(_)) {
+            return Err("Only numbers can be scaled".into());
+        }
+
+        if let (Some(_), Value::Array(values)) = (format.count, value) {
+            return values.iter().map(|v| self.raw_single(v, &format.pack_type)).collect();
+        }
+
+        self.raw_single(value, &format.pack_type)
+    }
+
+    fn raw_single(&self, value: &Value, pack_type: &PackType) -> Result<Value, String> {
+        let n = value_as_f64(value).ok_or("Value should be a number")?;
+        let raw = n * self.scale + self.offset;
+
+        if pack_type.is_float() {
+            return f64_as_value(raw, format!("{:?}", raw));
+        }
+
+        let raw = self.rounding.apply(raw);
+
+        // the range of the pack type itself is checked when encoding
+        if raw >= i64::MIN as f64 && raw < i64::MAX as f64 {
+            Ok(Value::from(raw as i64))
+        } else if raw >= 0.0 && raw < u64::MAX as f64 {
+            Ok(Value::from(raw as u64))
+        } else {
+            Err(format!("Scaled value {} is out of range", raw))
+        }
+    }
+
+    /// Converts a decoded raw value, or an array of them, back to engineering values,
+    /// keeping whole values as integers where the `original` value was one
+    fn engineering(&self, raw: &Value, original: Option<&Value>) -> Result<Value, String> {
+        if let Value::Array(values) = raw {
+            let originals = original.and_then(Value::as_array);
+            return values
+                .iter()
+                .enumerate()
+                .map(|(i, v)| self.engineering(v, originals.and_then(|o| o.get(i))))
+                .collect();
+        }
+
+        let raw = value_as_f64(raw).ok_or("Raw value should be a number")?;
+        let value = (raw - self.offset) / self.scale;
+
+        let integer = original.is_some_and(|o| o.is_i64() || o.is_u64());
+        if integer && value.fract() == 0.0 && value >= i64::MIN as f64 && value < i64::MAX as f64 {
+            return Ok(Value::from(value as i64));
+        }
+
+        f64_as_value(value, format!("{:?}", value))
+    }
+}
+
+/// Resolves the value to encode for a key, which is either the value itself
+/// or taken from an object-form definition
+fn resolve_value(value: &Value, format: &PackFormat) -> Result<Value, String> {
+    let definition = match value {
+        Value::Object(definition) => definition,
+        _ => return Ok(value.clone()),
+    };
+
+    match definition.get("type").and_then(Value::as_str) {
+        Some(BITS_TYPE) => {
+            let names = bit_names(definition, format)?;
+
+            // the named booleans, or the raw word
+            match definition.get("value") {
+                None => Ok(Value::from(0)),
+                Some(Value::Object(bits)) => {
+                    let mut word = 0u64;
+
+                    for (name, set) in bits {
+                        let (bit, _) = names.iter().find(|(_, n)| n == name)
+                            .ok_or_else(|| format!("Unknown bit '{}'", name))?;
+
+                        if set.as_bool().ok_or_else(|| format!("Bit '{}' should be a boolean", name))? {
+                            word |= 1 << bit;
                         }
                     }
+
+                    Ok(Value::from(word))
                 }
-                other => {
-                    return Err(JsonError::Invalid(format!(
-                        "Key {} is outside modbus range",
-                        other
-                    )))
+                // bits without a name wouldn't survive being persisted
+                Some(raw) if raw.as_u64().is_some_and(|word| word & !bit_mask(&names) != 0) => {
+                    Err("Value sets bits without a name".into())
                 }
+                Some(raw) => Ok(raw.clone()),
             }
         }
+        Some(other) => Err(format!("Unknown type '{}'", other)),
+        None => {
+            let value = definition.get("value").ok_or("Missing 'value'")?;
 
-        Ok((registers, keys))
+            match Scaling::from_definition(definition)? {
+                Some(scaling) => scaling.raw(value, format),
+                None => Ok(value.clone()),
+            }
+        }
+    }
+}
+
+/// Puts a decoded value back into the form it was defined in, see `resolve_value`
+fn restore_value(definition: &Value, decoded: Value) -> Result<Value, String> {
+    let mut definition = match definition {
+        Value::Object(definition) => definition.clone(),
+        _ => return Ok(decoded),
+    };
+
+    if definition.get("type").and_then(Value::as_str) == Some(BITS_TYPE) {
+        let word = decoded.as_u64().ok_or("Bitfield should be an unsigned integer")?;
+        let bits: Map<String, Value> = definition
+            .get("bits")
+            .and_then(Value::as_object)
+            .ok_or("Missing 'bits' object")?
+            .iter()
+            .filter_map(|(bit, name)| {
+                let bit = bit.parse::<u32>().ok()?;
+                Some((name.as_str()?.to_string(), Value::Bool(word >> bit & 1 == 1)))
+            })
+            .collect();
+
+        definition.insert("value".into(), Value::Object(bits));
     } else {
-        return Err(JsonError::Invalid("data is not an object".into()));
+        let value = match Scaling::from_definition(&definition)? {
+            Some(scaling) => scaling.engineering(&decoded, definition.get("value"))?,
+            None => decoded,
+        };
+
+        definition.insert("value".into(), value);
+    }
+
+    Ok(Value::Object(definition))
+}
+
+/// Encodes the value of a whole key, which is an array for keys with a count
+fn encode_key(value: &Value, format: &PackFormat, settings: &Settings) -> Result<Vec<u16>, String> {
+    let order = format.order.unwrap_or(settings.order);
+
+    match format.count {
+        None => encode_value(value, &format.pack_type, order, settings.padding),
+        Some(count) => {
+            let values = value
+                .as_array()
+                .filter(|a| a.len() == count)
+                .ok_or_else(|| format!("Value should be an array of {} elements", count))?;
+
+            values
+                .iter()
+                .map(|v| encode_value(v, &format.pack_type, order, settings.padding))
+                .collect::<Result<Vec<Vec<u16>>, String>>()
+                .map(|words| words.concat())
+        }
+    }
+}
+
+/// Decodes the words of a whole key, see `encode_key`
+fn decode_key(words: &[u16], format: &PackFormat, settings: &Settings) -> Result<Value, String> {
+    let order = format.order.unwrap_or(settings.order);
+
+    match format.count {
+        None => decode_value(words, &format.pack_type, order),
+        Some(_) => words
+            .chunks(format.pack_type.len())
+            .map(|chunk| decode_value(chunk, &format.pack_type, order))
+            .collect::<Result<Vec<Value>, String>>()
+            .map(Value::Array),
     }
 }
 
 pub fn registers_to_object(
-    registers: &HashMap<u16, u16>,
-    keys: Vec<String>,
+    registers: &HashMap<u32, u16>,
+    layout: &Layout,
 ) -> Result<serde_json::Value, JsonError> {
     let mut json: Map<String, Value> = Map::new();
+    let settings = Settings::from_layout(layout)?;
+
+    for (key, value) in layout {
+        if is_setting(key) {
+            json.insert(key.to_string(), value.clone());
+            continue;
+        }
 
-    for key in keys {
-        let PackFormat { address, pack_type } = PackFormat::parse(key.as_str())
+        let format = PackFormat::parse(key.as_str())
             .map_err(|_| JsonError::Other(format!("Failed to parse {}", key)))?;
+        let address = format.address;
 
         // Collect bytes based on specific addresses
-        let bytes: Vec<&u16> = (address..address + pack_type.len() as u16)
+        let bytes: Vec<&u16> = (address..address + format.len() as u32)
             .filter_map(|addr| registers.get(&addr))
             .collect();
 
-        if bytes.len() < pack_type.len() {
+        if bytes.len() < format.len() {
             return Err(JsonError::Other(format!(
                 "Mismatching length at address {}",
                 key
             )));
         }
 
-        let number_str = match pack_type {
-            PackType::U16 => (*bytes[0] as u16).to_string(),
-            PackType::I16 => (*bytes[0] as i16).to_string(),
-
-            // Concatenating two u16s to form a u32
-            PackType::U32 => (((*bytes[0] as u32) << 16) | (*bytes[1] as u32)).to_string(),
-
-            // Concatenating two u16s to form an i32
-            PackType::I32 => ((((*bytes[0] as u32) << 16) | (*bytes[1] as u32)) as i32).to_string(),
-
-            // Concatenating four u16s to form a u64
-            PackType::U64 => (((*bytes[0] as u64) << 48)
-                | ((*bytes[1] as u64) << 32)
-                | ((*bytes[2] as u64) << 16)
-                | (*bytes[3] as u64))
-                .to_string(),
-
-            // Concatenating four u16s to form an i64
-            PackType::I64 => ((((*bytes[0] as u64) << 48)
-                | ((*bytes[1] as u64) << 32)
-                | ((*bytes[2] as u64) << 16)
-                | (*bytes[3] as u64)) as i64)
-                .to_string(),
-        };
-
-        let value = serde_json::Number::from_str(number_str.as_str())
-            .map_err(|_| JsonError::Other(format!("Error creating serde number value")))?;
+        let words: Vec<u16> = bytes.into_iter().copied().collect();
+        let value = decode_key(&words, &format, &settings)
+            .and_then(|decoded| restore_value(value, decoded))
+            .map_err(JsonError::Other)?;
 
         if json
-            .insert(key.to_string(), serde_json::Value::Number(value))
+            .insert(key.to_string(), value)
             .is_some()
         {
-            return Err(JsonError::Invalid(format!("Overwrote json map")));
+            return Err(JsonError::Invalid("Overwrote json map".to_string()));
         }
     }
 
@@ -215,9 +1059,17 @@ mod tests {
     use std::fs;
 
     use super::*;
+    use crate::util::AsWords;
     use serde_json::json;
     type Error = Box<dyn std::error::Error>;
 
+    fn layout(value: Value) -> Layout {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("expected a json object"),
+        }
+    }
+
     #[test]
     pub fn test_parse() -> Result<(), Error> {
         let data = json!({
@@ -236,28 +1088,27 @@ mod tests {
             "40300/q": -1,
         });
 
-        let (registers, _): (HashMap<u16, u16>, Vec<String>) =
-            parse(data).map_err(|e| e.to_string())?;
+        let JsonResult { registers, .. } = parse(data).map_err(|e| e.to_string())?;
 
-        assert!(registers.get(&40003).unwrap() == &(124i16 as u16));
-        assert!(registers.get(&40004).unwrap() == &(124i16 as u16));
-        assert!(registers.get(&40100).unwrap() == &(-1i16 as u16));
-        assert!(registers.get(&40008).unwrap() == &(32u16));
+        assert!(registers.get(&400003).unwrap() == &(124i16 as u16));
+        assert!(registers.get(&400004).unwrap() == &(124i16 as u16));
+        assert!(registers.get(&400100).unwrap() == &(-1i16 as u16));
+        assert!(registers.get(&400008).unwrap() == &(32u16));
         assert_eq!(
             [
-                *registers.get(&40200).unwrap(),
-                *registers.get(&40201).unwrap(),
+                *registers.get(&400200).unwrap(),
+                *registers.get(&400201).unwrap(),
             ],
-            [0xFFFF as u16, 0xFFF6 as u16]
+            [0xFFFF_u16, 0xFFF6_u16]
         );
         assert_eq!(
             [
-                *registers.get(&40300).unwrap(),
-                *registers.get(&40301).unwrap(),
-                *registers.get(&40302).unwrap(),
-                *registers.get(&40303).unwrap(),
+                *registers.get(&400300).unwrap(),
+                *registers.get(&400301).unwrap(),
+                *registers.get(&400302).unwrap(),
+                *registers.get(&400303).unwrap(),
             ],
-            [0xFFFF as u16, 0xFFFF as u16, 0xFFFF as u16, 0xFFFF as u16]
+            [0xFFFF_u16, 0xFFFF_u16, 0xFFFF_u16, 0xFFFF_u16]
         );
 
         Ok(())
@@ -265,69 +1116,360 @@ mod tests {
 
     #[test]
     pub fn test_register_to_object() -> Result<(), Error> {
-        let registers: HashMap<u16, u16> = HashMap::from([
+        let registers: HashMap<u32, u16> = HashMap::from([
             (1, -1i16 as u16),
             (2, 1),
             (3, 1),
             (9, 9),
-            (40001, 0xFFFF),
-            (40002, 0xFF9C),
-            (40200, 0x0000),
-            (40201, 0x0000),
-            (40202, 0x0000),
-            (40203, 0xFFFF),
+            (400001, 0xFFFF),
+            (400002, 0xFF9C),
+            (400200, 0x0000),
+            (400201, 0x0000),
+            (400202, 0x0000),
+            (400203, 0xFFFF),
         ]);
 
-        let keys: Vec<String> = vec![
-            "1/h".into(),
-            "2".into(),
-            "3".into(),
-            "9".into(),
-            "40001/i".into(),
-            "40200/Q".into(),
-        ];
+        let layout = layout(json!({
+            "1/h": 0,
+            "2": 0,
+            "3": 0,
+            "9": 0,
+            "40001/i": 0,
+            "40200/Q": 0,
+        }));
 
         if let Value::Object(map) =
-            registers_to_object(&registers, keys).map_err(|e| e.to_string())?
+            registers_to_object(&registers, &layout).map_err(|e| e.to_string())?
         {
             assert_eq!(map.get("1/h").unwrap().to_string(), "-1");
             assert_eq!(map.get("40001/i").unwrap().to_string(), "-100");
             assert_eq!(map.get("40200/Q").unwrap().to_string(), "65535")
         } else {
-            assert!(false);
+            panic!("expected a json object");
         };
 
         Ok(())
     }
 
+    #[test]
+    pub fn test_float_round_trip() -> Result<(), Error> {
+        let data = json!({
+            "40001/f": 21.5,
+            "40003/d": -0.1,
+            "40007/e": 1.5,
+            "40008/f": "NaN",
+            "40010/d": "-Infinity",
+        });
+
+        let JsonResult { registers, layout, .. } = parse(data.clone()).map_err(|e| e.to_string())?;
+
+        assert_eq!(
+            [*registers.get(&400001).unwrap(), *registers.get(&400002).unwrap()],
+            [0x41AC, 0x0000]
+        );
+        assert_eq!(*registers.get(&400007).unwrap(), 0x3E00);
+
+        assert_eq!(registers_to_object(&registers, &layout).map_err(|e| e.to_string())?, data);
+
+        assert!(parse(json!({ "40001/e": 100000.0 })).is_err());
+        assert!(parse(json!({ "40001/f": 1e300 })).is_err());
+        assert!(parse(json!({ "40001/H": "NaN" })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_byte_order() -> Result<(), Error> {
+        let data = json!({
+            "byte_order": "CDAB",
+            "40001/I": 0xAABBCCDDu32,
+            "40003/>I": 0xAABBCCDDu32,
+            "40005/<I": 0xAABBCCDDu32,
+            "40007/BADC:I": 0xAABBCCDDu32,
+            "40009/<H": 0xAABB,
+        });
+
+        let JsonResult { registers, layout, .. } = parse(data.clone()).map_err(|e| e.to_string())?;
+        let words = |addr: u32, len: u32| -> Vec<u16> {
+            (addr..addr + len).map(|a| *registers.get(&a).unwrap()).collect()
+        };
+
+        assert_eq!(words(400001, 2), [0xCCDD, 0xAABB]);
+        assert_eq!(words(400003, 2), [0xAABB, 0xCCDD]);
+        assert_eq!(words(400005, 2), [0xDDCC, 0xBBAA]);
+        assert_eq!(words(400007, 2), [0xBBAA, 0xDDCC]);
+        assert_eq!(words(400009, 1), [0xBBAA]);
+
+        assert_eq!(registers_to_object(&registers, &layout).map_err(|e| e.to_string())?, data);
+
+        assert!(parse(json!({ "byte_order": "ACBD" })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_string() -> Result<(), Error> {
+        let data = json!({
+            "string_padding": "space",
+            "40100/5s": "ABC",
+            "40103/<4s": "ABCD",
+            "40105/s": "",
+        });
+
+        let JsonResult { registers, layout, .. } = parse(data.clone()).map_err(|e| e.to_string())?;
+
+        assert_eq!(*registers.get(&400100).unwrap(), u16::from_be_bytes(*b"AB"));
+        assert_eq!(*registers.get(&400101).unwrap(), u16::from_be_bytes(*b"C "));
+        assert_eq!(*registers.get(&400102).unwrap(), u16::from_be_bytes(*b"  "));
+        assert_eq!(*registers.get(&400103).unwrap(), u16::from_be_bytes(*b"BA"));
+        assert_eq!(*registers.get(&400104).unwrap(), u16::from_be_bytes(*b"DC"));
+        assert_eq!(*registers.get(&400105).unwrap(), u16::from_be_bytes(*b"  "));
+
+        assert_eq!(registers_to_object(&registers, &layout).map_err(|e| e.to_string())?, data);
+
+        let JsonResult { registers, .. } = parse(json!({ "40001/4s": "AB" })).map_err(|e| e.to_string())?;
+        assert_eq!(*registers.get(&400002).unwrap(), 0);
+
+        assert!(parse(json!({ "40001/4s": "ABCDE" })).is_err());
+        assert!(parse(json!({ "40001/4s": "ÆØÅ" })).is_err());
+        assert!(parse(json!({ "40001/4s": 12 })).is_err());
+        assert!(parse(json!({ "465535/8s": "ABC" })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_array() -> Result<(), Error> {
+        let data = json!({
+            "1/4H": [1, 0, 0, 1],
+            "40001/3H": [1, 2, 3],
+            "40004/2i": [-1, 10],
+            "40008/2f": [0.5, "NaN"],
+        });
+
+        let JsonResult { registers, layout, .. } = parse(data.clone()).map_err(|e| e.to_string())?;
+
+        assert_eq!(*registers.get(&4).unwrap(), 1);
+        assert_eq!(*registers.get(&400003).unwrap(), 3);
+        assert_eq!(
+            (400004..400008).map(|a| *registers.get(&a).unwrap()).collect::<Vec<u16>>(),
+            [0xFFFF, 0xFFFF, 0x0000, 0x000A]
+        );
+        assert!(!registers.contains_key(&400012));
+
+        assert_eq!(registers_to_object(&registers, &layout).map_err(|e| e.to_string())?, data);
+
+        assert!(parse(json!({ "40001/3H": [1, 2] })).is_err());
+        assert!(parse(json!({ "40001/3H": 1 })).is_err());
+        assert!(parse(json!({ "40001/3H": [1, 2, 3], "40003": 4 })).is_err());
+        assert!(parse(json!({ "1/2H": [1, 2] })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_bytes() -> Result<(), Error> {
+        let data = json!({
+            "40010/BB": [0x12, 0x34],
+            "40011/<bB": [-1, 2],
+            "40012/?x": [true],
+            "40013/3B": [1, 2, 3],
+        });
+
+        let JsonResult { registers, layout, .. } = parse(data.clone()).map_err(|e| e.to_string())?;
+
+        assert_eq!(*registers.get(&400010).unwrap(), 0x1234);
+        assert_eq!(*registers.get(&400011).unwrap(), 0x02FF);
+        assert_eq!(*registers.get(&400012).unwrap(), 0x0100);
+        assert_eq!(*registers.get(&400013).unwrap(), 0x0102);
+        assert_eq!(*registers.get(&400014).unwrap(), 0x0300);
+
+        assert_eq!(registers_to_object(&registers, &layout).map_err(|e| e.to_string())?, data);
+
+        let JsonResult { registers, .. } = parse(json!({ "40001/??": [1, 0] })).map_err(|e| e.to_string())?;
+        assert_eq!(*registers.get(&400001).unwrap(), 0x0100);
+
+        assert!(parse(json!({ "40001/BB": [256, 0] })).is_err());
+        assert!(parse(json!({ "40001/bB": [-129, 0] })).is_err());
+        assert!(parse(json!({ "40001/?x": [true, false] })).is_err());
+        assert!(parse(json!({ "1/BB": 1 })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_bits() -> Result<(), Error> {
+        let data = json!({
+            "40010": {
+                "type": "bits",
+                "bits": { "0": "running", "3": "fault", "15": "remote" },
+                "value": { "running": true, "remote": true },
+            },
+            "40011/I": { "type": "bits", "bits": { "31": "alarm" }, "value": 2147483648u32 },
+            "40013": { "type": "bits", "bits": { "1": "ready" } },
+        });
+
+        let JsonResult { mut registers, layout, .. } = parse(data).map_err(|e| e.to_string())?;
+
+        assert_eq!(*registers.get(&400010).unwrap(), 0x8001);
+        assert_eq!(*registers.get(&400011).unwrap(), 0x8000);
+        assert_eq!(*registers.get(&400013).unwrap(), 0x0000);
+
+        registers.insert(400010, 0x0008);
+
+        let object = registers_to_object(&registers, &layout).map_err(|e| e.to_string())?;
+
+        assert_eq!(
+            object["40010"],
+            json!({
+                "type": "bits",
+                "bits": { "0": "running", "3": "fault", "15": "remote" },
+                "value": { "running": false, "fault": true, "remote": false },
+            })
+        );
+        assert_eq!(object["40011/I"]["value"], json!({ "alarm": true }));
+        assert_eq!(object["40013"]["value"], json!({ "ready": false }));
+
+        assert!(parse(json!({ "40001": { "type": "bits", "bits": { "16": "x" } } })).is_err());
+        assert!(parse(json!({ "40001/h": { "type": "bits", "bits": { "0": "x" } } })).is_err());
+        assert!(parse(json!({ "40001": { "type": "bits", "bits": { "0": "x" }, "value": { "y": true } } })).is_err());
+        assert!(parse(json!({ "40001": { "type": "nope" } })).is_err());
+        assert!(parse(json!({ "40001": { "type": "bits", "bits": { "0": "x" }, "value": 3 } })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_scaling() -> Result<(), Error> {
+        let data = json!({
+            "40001/h": { "value": 21.5, "scale": 10 },
+            "40002": { "value": -20, "scale": 2, "offset": 100 },
+            "40003/2H": { "value": [1.25, 2], "scale": 4, "unit": "A" },
+            "40005/f": { "value": 1.5, "scale": 2, "offset": 0.5 },
+            "40007": { "value": 7 },
+            "40008": { "value": 20, "scale": 10 },
+            "40009": { "value": 3.0, "scale": 10 },
+        });
+
+        let JsonResult { registers, layout, .. } = parse(data.clone()).map_err(|e| e.to_string())?;
+
+        assert_eq!(*registers.get(&400001).unwrap(), 215);
+        assert_eq!(*registers.get(&400002).unwrap(), 60);
+        assert_eq!(*registers.get(&400003).unwrap(), 5);
+        assert_eq!(*registers.get(&400004).unwrap(), 8);
+        assert_eq!(
+            [*registers.get(&400005).unwrap(), *registers.get(&400006).unwrap()],
+            [0x4060, 0x0000]
+        );
+        assert_eq!(*registers.get(&400007).unwrap(), 7);
+        assert_eq!(*registers.get(&400008).unwrap(), 200);
+
+        // integers and floats are written back as they were given
+        assert_eq!(registers_to_object(&registers, &layout).map_err(|e| e.to_string())?, data);
+
+        // an integer whose raw value changed to one that isn't whole becomes a float
+        let mut registers = registers;
+        registers.insert(400008, 205);
+        let object = registers_to_object(&registers, &layout).map_err(|e| e.to_string())?;
+        assert_eq!(object["40008"]["value"], json!(20.5));
+
+        let rounded = |rounding: &str| -> Result<u16, String> {
+            let data = json!({ "40001": { "value": 1.25, "scale": 10, "rounding": rounding } });
+            parse(data).map(|r| *r.registers.get(&400001).unwrap()).map_err(|e| e.to_string())
+        };
+
+        assert_eq!(rounded("nearest")?, 13);
+        assert_eq!(rounded("floor")?, 12);
+        assert_eq!(rounded("ceil")?, 13);
+        assert_eq!(rounded("truncate")?, 12);
+
+        assert!(parse(json!({ "40001": { "value": 7000, "scale": 10 } })).is_err());
+        assert!(parse(json!({ "40001/h": { "value": -1, "offset": -32768 } })).is_err());
+        assert!(parse(json!({ "40001": { "value": 1, "scale": 0 } })).is_err());
+        assert!(parse(json!({ "40001/2s": { "value": "A", "scale": 2 } })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_object_form() -> Result<(), Error> {
+        let data = json!({
+            "1": { "value": 1, "name": "pump" },
+            "40001/I": { "value": 5, "name": "energy_total", "description": "Total energy", "unit": "Wh", "access": "ro" },
+            "40003": 7,
+        });
+
+        let JsonResult { mut registers, layout, metadata } = parse(data.clone()).map_err(|e| e.to_string())?;
+
+        assert_eq!(*registers.get(&1).unwrap(), 1);
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata[1].address, 400001);
+        assert_eq!(metadata[1].len, 2);
+
+        registers.insert(400002, 6);
+
+        let mut expected = data.clone();
+        expected["40001/I"]["value"] = json!(6);
+
+        assert_eq!(registers_to_object(&registers, &layout).map_err(|e| e.to_string())?, expected);
+
+        // a key spanning the whole table still covers its last register
+        let JsonResult { metadata, .. } = parse(json!({
+            "400001/65536H": { "value": vec![0; 65536], "access": "ro" },
+        }))
+        .map_err(|e| e.to_string())?;
+        assert_eq!(metadata[0].len, 65536);
+        assert!(metadata[0].contains(465536));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_min_max() -> Result<(), Error> {
+        let JsonResult { metadata, .. } = parse(json!({
+            "40001/2q": { "value": [-5, 5], "min": -9007199254740993i64, "max": 10 },
+        }))
+        .map_err(|e| e.to_string())?;
+
+        assert!(metadata[0].in_range(&[0, 0, 0, 1, 0, 0, 0, 10]));
+        assert!(!metadata[0].in_range(&[0, 0, 0, 1, 0, 0, 0, 11]));
+        assert!(!metadata[0].in_range(&(-9007199254740994i64).as_words().repeat(2)));
+
+        assert!(parse(json!({ "40001": { "value": 5, "min": 10 } })).is_err());
+        assert!(parse(json!({ "40001/h": { "value": 21.5, "scale": 10, "max": 200 } })).is_err());
+        assert!(parse(json!({ "40001/2s": { "value": "A", "max": 1 } })).is_err());
+
+        Ok(())
+    }
+
     #[test]
     pub fn test_write() -> Result<(), Error> {
         let path = "test-output.json";
-        let registers: HashMap<u16, u16> = HashMap::from([
+        let registers: HashMap<u32, u16> = HashMap::from([
             (1, -1i16 as u16),
             (2, 1),
             (3, 1),
             (9, 9),
-            (40001, 0xFFFF),
-            (40002, 0xFF9C),
-            (40200, 0x0000),
-            (40201, 0x0000),
-            (40202, 0x0000),
-            (40203, 0xFFFF),
+            (400001, 0xFFFF),
+            (400002, 0xFF9C),
+            (400200, 0x0000),
+            (400201, 0x0000),
+            (400202, 0x0000),
+            (400203, 0xFFFF),
         ]);
-        let keys: Vec<String> = vec![
-            "1/h".into(),
-            "2".into(),
-            "3".into(),
-            "9".into(),
-            "40001/i".into(),
-            "40200/Q".into(),
-        ];
-
-        let value = registers_to_object(&registers, keys).unwrap();
+        let layout = layout(json!({
+            "1/h": 0,
+            "2": 0,
+            "3": 0,
+            "9": 0,
+            "40001/i": 0,
+            "40200/Q": 0,
+        }));
+
+        let value = registers_to_object(&registers, &layout).unwrap();
         let value_str = serde_json::to_string_pretty(&value).unwrap();
 
-        write(value, path).unwrap();
+        write(value, path, 0).unwrap();
 
         let mut buf = String::new();
         File::open(path).unwrap().read_to_string(&mut buf).unwrap();
@@ -338,4 +1480,37 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_backups() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("rust-modbus-backups-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("data.json").to_string_lossy().to_string();
+
+        for i in 1..=4 {
+            write(json!({ "40001": i }), &path, 2).unwrap();
+        }
+
+        // the two previous versions are kept, nothing else is left behind
+        assert_eq!(load(&path).unwrap(), json!({ "40001": 4 }));
+        assert_eq!(read(&backup_path(&path, 1)).unwrap(), json!({ "40001": 3 }));
+        assert_eq!(read(&backup_path(&path, 2)).unwrap(), json!({ "40001": 2 }));
+        assert!(!Path::new(&backup_path(&path, 3)).exists());
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+        // a torn write of the file falls back to the newest valid backup
+        fs::write(&path, "{ \"40001\": ")?;
+        assert_eq!(load(&path).unwrap(), json!({ "40001": 3 }));
+
+        fs::write(backup_path(&path, 1), "")?;
+        assert_eq!(load(&path).unwrap(), json!({ "40001": 2 }));
+
+        fs::write(backup_path(&path, 2), "")?;
+        assert!(matches!(load(&path), Err(JsonError::Other(_))));
+
+        fs::remove_dir_all(&dir)?;
+        assert!(matches!(load(&path), Err(JsonError::NoFile)));
+
+        Ok(())
+    }
 }
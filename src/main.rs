@@ -3,31 +3,62 @@ use std::{
     time::Duration,
 };
 
-use clap::Parser;
+use clap::{ArgGroup, Parser};
 use fern::Dispatch;
 use log::LevelFilter;
 use server::ServerConfig;
-use validation::{validate_time, parse_whitelist};
-
+use addressing::Addressing;
+use framing::Framing;
+use serial::{Parity, SerialConfig};
+use tls::TlsConfig;
+use units::UnknownUnit;
+use gateway::Route;
+use persistence::Policy;
+use validation::{validate_time, parse_role, parse_route, parse_unit, parse_whitelist, Permissions};
+
+mod addressing;
+mod device_id;
+mod diagnostics;
+mod framing;
+mod gateway;
 mod json;
 mod pack;
+mod persistence;
+mod poll;
 mod register_manager;
+mod serial;
 mod server;
 mod service;
+mod tls;
+mod udp;
+mod units;
 mod util;
 mod validation;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[command(group(ArgGroup::new("transport").args(["serial", "udp", "tls"]).multiple(true)))]
 struct Args {
     /// target IP address and port
     #[arg(default_value = "0.0.0.0:502")]
     target: SocketAddr,
 
-    /// How often to update persistence
+    /// How often to check the register files for changes to persist
     #[clap(short('f'), default_value = "1s", value_parser = validate_time)]
     update_frequency: Duration,
 
+    /// Quiet time after the last change before a register file is persisted
+    #[clap(long, default_value = "2s", value_parser = validate_time)]
+    debounce: Duration,
+
+    /// Longest a change may wait to be persisted while changes keep coming
+    #[clap(long, default_value = "30s", value_parser = validate_time)]
+    max_latency: Duration,
+
+    /// Previous versions of each register file to keep, as <file>.1 (newest) to <file>.<n>
+    #[clap(long, default_value_t = json::DEFAULT_BACKUPS)]
+    backups: usize,
+
     /// Log Level (off, error, info, warn, trace)
     #[clap(short, default_value = "info", value_enum)]
     loglevel: log::LevelFilter,
@@ -35,6 +66,79 @@ struct Args {
     /// CIDR Whitelist (r/w/rw) (comma separated)
     #[clap(short = 'W', use_value_delimiter = true )]
     whitelist: Vec<String>,
+
+    /// Register file of a unit ID, e.g. 2=inverter.json (repeatable)
+    #[clap(short = 'U', long = "unit", value_parser = parse_unit)]
+    units: Vec<(u8, String)>,
+
+    /// Response to unit IDs without their own register file
+    #[clap(long, default_value = "default", value_enum)]
+    unknown_unit: UnknownUnit,
+
+    /// How request addresses map to register keys, unless a register file sets "addressing"
+    #[clap(short = 'a', long, default_value = "legacy", value_enum)]
+    addressing: Addressing,
+
+    /// Framing of requests on the listener
+    #[clap(long, default_value = "tcp", value_enum)]
+    framing: Framing,
+
+    /// Serial device to serve as an RTU slave, e.g. /dev/ttyUSB0
+    #[clap(long)]
+    serial: Option<String>,
+
+    /// Baud rate of the serial line
+    #[clap(long, default_value = "19200")]
+    baud: u32,
+
+    /// Parity of the serial line
+    #[clap(long, default_value = "even", value_enum)]
+    parity: Parity,
+
+    /// Stop bits of the serial line
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u8).range(1..=2))]
+    stop_bits: u8,
+
+    /// Unit ID answered from data.json on the serial line
+    #[clap(long, default_value = "1")]
+    slave_id: u8,
+
+    /// Silence ending a frame on the serial line [default: 3.5 characters, at least 20ms]
+    #[clap(long, value_parser = validate_time)]
+    frame_gap: Option<Duration>,
+
+    /// Address to answer Modbus/UDP datagrams on, e.g. 0.0.0.0:502
+    #[clap(long)]
+    udp: Option<SocketAddr>,
+
+    /// Address to serve Modbus/TCP Security on, e.g. 0.0.0.0:802
+    #[clap(long, requires_all = ["tls_cert", "tls_key", "tls_ca"])]
+    tls: Option<SocketAddr>,
+
+    /// PEM certificate chain of the server
+    #[clap(long)]
+    tls_cert: Option<String>,
+
+    /// PEM private key of the server
+    #[clap(long)]
+    tls_key: Option<String>,
+
+    /// PEM certificates of the CAs signing client certificates
+    #[clap(long)]
+    tls_ca: Option<String>,
+
+    /// Permissions of a client certificate role, e.g. operator=rw
+    #[clap(long = "role", value_parser = parse_role)]
+    roles: Vec<(String, Permissions)>,
+
+    /// Forward requests for units without a register file to an upstream server,
+    /// e.g. 5=10.0.0.2:502 or 5:400001-400100=10.0.0.2:502@500ms
+    #[clap(long = "route", value_parser = parse_route)]
+    routes: Vec<Route>,
+
+    /// Only serve the serial line, UDP or TLS, without a TCP listener
+    #[clap(long, requires = "transport")]
+    no_tcp: bool,
 }
 
 
@@ -64,8 +168,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     server::server_context(ServerConfig {
         socket_addr: args.target,
         update_frequency: args.update_frequency,
+        backups: args.backups,
+        persistence: Policy {
+            debounce: args.debounce,
+            max_latency: args.max_latency,
+        },
         read_whitelist,
-        write_whitelist
+        write_whitelist,
+        units: args.units,
+        unknown_unit: args.unknown_unit,
+        routes: args.routes,
+        addressing: args.addressing,
+        framing: args.framing,
+        tcp: !args.no_tcp,
+        udp: args.udp,
+        tls: args.tls.map(|socket_addr| TlsConfig {
+            socket_addr,
+            cert: args.tls_cert.unwrap_or_default(),
+            key: args.tls_key.unwrap_or_default(),
+            ca: args.tls_ca.unwrap_or_default(),
+            roles: args.roles.into_iter().collect(),
+        }),
+        serial: args.serial.map(|path| SerialConfig {
+            path,
+            baud_rate: args.baud,
+            parity: args.parity,
+            stop_bits: args.stop_bits,
+            slave_id: args.slave_id,
+            frame_gap: args.frame_gap,
+            addressing: args.addressing,
+        }),
     }).await?;
 
     Ok(())
@@ -74,7 +206,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 #[cfg(test)]
 mod test {
-    use crate::parse_whitelist;
+    use crate::{parse_unit, parse_whitelist};
 
     type Error = Box<dyn std::error::Error>;
 
@@ -98,4 +230,15 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    pub fn test_parse_unit() -> Result<(), Error> {
+
+        assert_eq!(parse_unit("2=inverter.json")?, (2, "inverter.json".to_string()));
+        assert!(parse_unit("256=inverter.json").is_err());
+        assert!(parse_unit("2=").is_err());
+        assert!(parse_unit("inverter.json").is_err());
+
+        Ok(())
+    }
+
 }
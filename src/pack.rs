@@ -1,11 +1,73 @@
-#[derive(PartialEq, Debug)]
+use crate::register_manager::RegisterType;
+
+#[derive(PartialEq, Debug, Clone, Default)]
 pub enum PackType {
+    #[default]
     U16,
     I16,
     U32,
     I32,
     U64,
-    I64
+    I64,
+    F16,
+    F32,
+    F64,
+    /// Fixed-length ASCII string of the given number of characters
+    String(usize),
+    /// Byte-wide fields packed high half first, e.g. 'BB' or '?x'
+    Bytes(Vec<ByteField>),
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ByteField {
+    I8,
+    U8,
+    Bool,
+    Pad,
+}
+
+impl ByteField {
+    fn from_char(value: &u8) -> Option<Self> {
+        match value {
+            b'b' => Some(ByteField::I8),
+            b'B' => Some(ByteField::U8),
+            b'?' => Some(ByteField::Bool),
+            b'x' => Some(ByteField::Pad),
+            _ => None
+        }
+    }
+
+    /// Parses a composite of byte fields where each field may be preceded by
+    /// a repeat count, e.g. '2B?x'
+    fn parse_all(format: &str) -> Result<Vec<Self>, PackError> {
+        let mut fields = Vec::new();
+        let mut digits = 0;
+
+        for (idx, c) in format.bytes().enumerate() {
+            if c.is_ascii_digit() {
+                digits += 1;
+                continue;
+            }
+
+            let field = ByteField::from_char(&c).ok_or(PackError::Unsupported)?;
+            let count = match digits {
+                0 => 1,
+                _ => format[idx - digits..idx].parse::<usize>().map_err(|_| PackError::Unsupported)?,
+            };
+
+            if count == 0 {
+                return Err(PackError::Unsupported);
+            }
+
+            fields.extend(std::iter::repeat_n(field, count));
+            digits = 0;
+        }
+
+        match (digits, fields.is_empty()) {
+            (0, false) => Ok(fields),
+            _ => Err(PackError::Unsupported),
+        }
+    }
 }
 
 impl PackType {
@@ -17,6 +79,9 @@ impl PackType {
             b'I' => Some(PackType::U32),
             b'q' => Some(PackType::I64),
             b'Q' => Some(PackType::U64),
+            b'e' => Some(PackType::F16),
+            b'f' => Some(PackType::F32),
+            b'd' => Some(PackType::F64),
             _ => None
         }
     }
@@ -24,11 +89,93 @@ impl PackType {
     pub fn len(&self) -> usize {
         match &self {
             PackType::U16 |
-            PackType::I16 => 1,
+            PackType::I16 |
+            PackType::F16 => 1,
             PackType::U32 |
-            PackType::I32 => 2,
+            PackType::I32 |
+            PackType::F32 => 2,
             PackType::U64 |
-            PackType::I64 => 4,
+            PackType::I64 |
+            PackType::F64 => 4,
+            // two characters or fields per register
+            PackType::String(chars) => chars.div_ceil(2),
+            PackType::Bytes(fields) => fields.len().div_ceil(2),
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, PackType::F16 | PackType::F32 | PackType::F64)
+    }
+}
+
+
+/// Byte layout of a value spanning one or more registers, named after how
+/// the bytes of a 32-bit value `0xAABBCCDD` end up on the wire
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum ByteOrder {
+    /// ABCD, high word first with high byte first
+    #[default]
+    BigEndian,
+    /// DCBA, low word first with low byte first
+    LittleEndian,
+    /// CDAB, low word first with high byte first
+    BigEndianWordSwap,
+    /// BADC, high word first with low byte first
+    LittleEndianWordSwap,
+}
+
+impl ByteOrder {
+    /// Parses either a python struct prefix (`>`, `!`, `<`) or an explicit
+    /// layout (`ABCD`, `DCBA`, `CDAB`, `BADC`)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            ">" | "!" | "ABCD" => Some(ByteOrder::BigEndian),
+            "<" | "DCBA" => Some(ByteOrder::LittleEndian),
+            "CDAB" => Some(ByteOrder::BigEndianWordSwap),
+            "BADC" => Some(ByteOrder::LittleEndianWordSwap),
+            _ => None,
+        }
+    }
+
+    /// Converts big-endian words to this order. Every order is its own
+    /// inverse, so this also converts words in this order back to big-endian.
+    pub fn apply(&self, words: &[u16]) -> Vec<u16> {
+        match self {
+            ByteOrder::BigEndian => words.to_vec(),
+            ByteOrder::LittleEndian => words.iter().rev().map(|w| w.swap_bytes()).collect(),
+            ByteOrder::BigEndianWordSwap => words.iter().rev().copied().collect(),
+            ByteOrder::LittleEndianWordSwap => words.iter().map(|w| w.swap_bytes()).collect(),
+        }
+    }
+
+    /// Whether the low byte of each register comes first
+    pub fn is_byte_swapped(&self) -> bool {
+        matches!(self, ByteOrder::LittleEndian | ByteOrder::LittleEndianWordSwap)
+    }
+}
+
+
+/// Filler for the unused tail of a string register block
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum StringPadding {
+    #[default]
+    Nul,
+    Space,
+}
+
+impl StringPadding {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "nul" => Some(StringPadding::Nul),
+            "space" => Some(StringPadding::Space),
+            _ => None,
+        }
+    }
+
+    pub fn byte(&self) -> u8 {
+        match self {
+            StringPadding::Nul => b'\0',
+            StringPadding::Space => b' ',
         }
     }
 }
@@ -36,36 +183,108 @@ impl PackType {
 
 #[derive(PartialEq, Debug)]
 pub struct PackFormat {
-    pub address: u16,
+    /// First register of the key in 6-digit notation, e.g. 400001
+    pub address: u32,
     pub pack_type: PackType,
+    /// Explicit byte order of the key, `None` means the file default
+    pub order: Option<ByteOrder>,
+    /// Number of consecutive values for array keys like '50H'
+    pub count: Option<usize>,
 }
 
 
 impl PackFormat {
+    /// Number of registers spanned by the whole key
+    pub fn len(&self) -> usize {
+        self.count.unwrap_or(1) * self.pack_type.len()
+    }
+
     pub fn parse(addr: &str) -> Result<Self, PackError> {
         // Check if there's a '/' in the string
         if let Some(idx) = addr.find('/') {
             // Parse the address (before the '/')
-            let address = addr[..idx].parse::<u16>().map_err(|_| PackError::Unsupported)?;
+            let address = Self::parse_address(&addr[..idx])?;
 
             // Get the part after the '/'
-            addr.get(idx + 1..)
-                .ok_or(PackError::Unsupported)  // Error if nothing after the '/'
-                .and_then(|type_slice| match type_slice.as_bytes() {
-                    // Check if it's a valid single character format
-                    [format] => {
-                        PackType::from_char(format)
-                            .ok_or(PackError::Unsupported)  // Handle unsupported pack type
-                    },
-                    _ => Err(PackError::Unsupported),  // Error if invalid format
-                })
-                .map(|pack_type| PackFormat { address, pack_type })
+            let format = addr.get(idx + 1..).ok_or(PackError::Unsupported)?;
+
+            // Split off the byte order, either 'CDAB:I' or a struct prefix like '<I'
+            let (order, type_slice) = match format.split_once(':') {
+                Some((order, rest)) => (Some(ByteOrder::parse(order).ok_or(PackError::Unsupported)?), rest),
+                None => match format.get(..1).and_then(ByteOrder::parse) {
+                    Some(order) => (Some(order), &format[1..]),
+                    None => (None, format),
+                },
+            };
+
+            // Byte-wide composites carry their own counts, e.g. '2B?x'
+            if type_slice.ends_with(['b', 'B', '?', 'x']) {
+                return ByteField::parse_all(type_slice)
+                    .map(|fields| PackFormat { address, pack_type: PackType::Bytes(fields), order, count: None });
+            }
+
+            // Split off a leading count, e.g. the '16' in '16s'
+            let digits = type_slice.bytes().take_while(|b| b.is_ascii_digit()).count();
+            let count = match digits {
+                0 => None,
+                _ => Some(type_slice[..digits].parse::<usize>().map_err(|_| PackError::Unsupported)?),
+            };
+
+            match (count, &type_slice.as_bytes()[digits..]) {
+                // Strings take their length as the count, defaulting to a single character
+                (count, [b's']) => match count.unwrap_or(1) {
+                    0 => Err(PackError::Unsupported),
+                    chars => Ok((PackType::String(chars), None)),
+                },
+                (Some(0), _) => Err(PackError::Unsupported),  // Error on empty arrays
+                // Check if it's a valid single character format
+                (count, [format]) => {
+                    PackType::from_char(format)
+                        .map(|pack_type| (pack_type, count))
+                        .ok_or(PackError::Unsupported)  // Handle unsupported pack type
+                },
+                _ => Err(PackError::Unsupported),  // Error if invalid format
+            }
+            .map(|(pack_type, count)| PackFormat { address, pack_type, order, count })
         } else {
             // No '/', default to U16 and parse the address
-            let address = addr.parse::<u16>().map_err(|_| PackError::Unsupported)?;
-            Ok(PackFormat { address, pack_type: PackType::U16 })
+            let address = Self::parse_address(addr)?;
+            Ok(PackFormat { address, pack_type: PackType::U16, order: None, count: None })
         }
     }
+
+    /// Parses the register part of a key into 6-digit notation. Accepts
+    /// 5-digit (`40001`), 6-digit (`400001`) and prefixed (`hr:0`) keys.
+    fn parse_address(addr: &str) -> Result<u32, PackError> {
+        // Prefixed keys hold the 0-based offset within the table
+        if let Some((prefix, offset)) = addr.split_once(':') {
+            let table = RegisterType::from_prefix(prefix).ok_or(PackError::Unsupported)?;
+            let offset = offset
+                .bytes()
+                .all(|b| b.is_ascii_digit())
+                .then(|| offset.parse::<u16>().ok())
+                .flatten()
+                .ok_or(PackError::Unsupported)?;
+
+            return Ok(table.first() + offset as u32);
+        }
+
+        if !addr.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(PackError::Unsupported);
+        }
+
+        let number = addr.parse::<u32>().map_err(|_| PackError::Unsupported)?;
+
+        match (addr.len(), number) {
+            (6, _) => RegisterType::locate(number).map(|_| number),
+            (1..=5, 1..=9999) => Some(number),
+            (1..=5, 10001..=19999 | 30001..=39999 | 40001..=49999) => {
+                Some(number / 10000 * 100000 + number % 10000)
+            }
+            _ => None,
+        }
+        .ok_or(PackError::Unsupported)
+    }
 }
 
 
@@ -83,12 +302,52 @@ pub mod test {
     #[test]
     pub fn test_packformat_parse() -> Result<(), Box<dyn std::error::Error>> {
 
-        assert_eq!(PackFormat::parse("40001/h").unwrap(), PackFormat { address: 40001, pack_type: PackType::I16 });
-        assert_eq!(PackFormat::parse("40311/H").unwrap(), PackFormat { address: 40311, pack_type: PackType::U16 });
-        assert_eq!(PackFormat::parse("40311/i").unwrap(), PackFormat { address: 40311, pack_type: PackType::I32 });
-        assert_eq!(PackFormat::parse("40311/I").unwrap(), PackFormat { address: 40311, pack_type: PackType::U32 });
+        assert_eq!(PackFormat::parse("40001/h").unwrap(), PackFormat { address: 400001, pack_type: PackType::I16, order: None, count: None });
+        assert_eq!(PackFormat::parse("40311/H").unwrap(), PackFormat { address: 400311, pack_type: PackType::U16, order: None, count: None });
+        assert_eq!(PackFormat::parse("40311/i").unwrap(), PackFormat { address: 400311, pack_type: PackType::I32, order: None, count: None });
+        assert_eq!(PackFormat::parse("40311/I").unwrap(), PackFormat { address: 400311, pack_type: PackType::U32, order: None, count: None });
+        assert_eq!(PackFormat::parse("40311/e").unwrap(), PackFormat { address: 400311, pack_type: PackType::F16, order: None, count: None });
+        assert_eq!(PackFormat::parse("40311/f").unwrap(), PackFormat { address: 400311, pack_type: PackType::F32, order: None, count: None });
+        assert_eq!(PackFormat::parse("40311/d").unwrap(), PackFormat { address: 400311, pack_type: PackType::F64, order: None, count: None });
         assert_eq!(PackFormat::parse("40311/<"), Err(PackError::Unsupported));
-        assert_eq!(PackFormat::parse("40311").unwrap(), PackFormat { address: 40311, pack_type: PackType::U16});
+        assert_eq!(PackFormat::parse("40311/<I").unwrap(), PackFormat { address: 400311, pack_type: PackType::U32, order: Some(ByteOrder::LittleEndian), count: None });
+        assert_eq!(PackFormat::parse("40311/!q").unwrap(), PackFormat { address: 400311, pack_type: PackType::I64, order: Some(ByteOrder::BigEndian), count: None });
+        assert_eq!(PackFormat::parse("40311/CDAB:f").unwrap(), PackFormat { address: 400311, pack_type: PackType::F32, order: Some(ByteOrder::BigEndianWordSwap), count: None });
+        assert_eq!(PackFormat::parse("40311/XYZW:f"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("40100/16s").unwrap(), PackFormat { address: 400100, pack_type: PackType::String(16), order: None, count: None });
+        assert_eq!(PackFormat::parse("40100/<5s").unwrap(), PackFormat { address: 400100, pack_type: PackType::String(5), order: Some(ByteOrder::LittleEndian), count: None });
+        assert_eq!(PackFormat::parse("40100/s").unwrap(), PackFormat { address: 400100, pack_type: PackType::String(1), order: None, count: None });
+        assert_eq!(PackFormat::parse("40100/0s"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("40001/50H").unwrap(), PackFormat { address: 400001, pack_type: PackType::U16, order: None, count: Some(50) });
+        assert_eq!(PackFormat::parse("40001/<10i").unwrap(), PackFormat { address: 400001, pack_type: PackType::I32, order: Some(ByteOrder::LittleEndian), count: Some(10) });
+        assert_eq!(PackFormat::parse("40001/0H"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("40001/10"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("40001/10i").unwrap().len(), 20);
+        assert_eq!(PackFormat::parse("40010/BB").unwrap(), PackFormat { address: 400010, pack_type: PackType::Bytes(vec![ByteField::U8, ByteField::U8]), order: None, count: None });
+        assert_eq!(PackFormat::parse("40010/<?x").unwrap(), PackFormat { address: 400010, pack_type: PackType::Bytes(vec![ByteField::Bool, ByteField::Pad]), order: Some(ByteOrder::LittleEndian), count: None });
+        assert_eq!(PackFormat::parse("40010/2b3B").unwrap().pack_type, PackType::Bytes(vec![ByteField::I8, ByteField::I8, ByteField::U8, ByteField::U8, ByteField::U8]));
+        assert_eq!(PackFormat::parse("40010/2b3B").unwrap().len(), 3);
+        assert_eq!(PackFormat::parse("40010/hB"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("40010/0B"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("40311").unwrap(), PackFormat { address: 400311, pack_type: PackType::U16, order: None, count: None });
+
+        assert_eq!(PackFormat::parse("1").unwrap().address, 1);
+        assert_eq!(PackFormat::parse("10001").unwrap().address, 100001);
+        assert_eq!(PackFormat::parse("465536/f").unwrap().address, 465536);
+        assert_eq!(PackFormat::parse("065536").unwrap().address, 65536);
+        assert_eq!(PackFormat::parse("hr:0").unwrap().address, 400001);
+        assert_eq!(PackFormat::parse("ir:70/CDAB:f").unwrap().address, 300071);
+        assert_eq!(PackFormat::parse("co:65535").unwrap().address, 65536);
+        assert_eq!(PackFormat::parse("di:12/2H").unwrap().address, 100013);
+        assert_eq!(PackFormat::parse("400000"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("465537"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("200001"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("20001"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("0"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("hr:65536"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("hr:+1"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("xx:1"), Err(PackError::Unsupported));
+        assert_eq!(PackFormat::parse("4000001"), Err(PackError::Unsupported));
 
         Ok(())
     }
@@ -0,0 +1,117 @@
+use std::time::{Duration, Instant};
+
+/// When to write a register file back after its registers change
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    /// Quiet time after the last change, so a burst of writes is persisted once
+    pub debounce: Duration,
+    /// Longest a change may wait for the quiet time before it's persisted anyway
+    pub max_latency: Duration,
+}
+
+/// Tracks the generation of one register file between checks of the persistence thread
+pub struct Tracker {
+    /// Generation last written to the file
+    persisted: u64,
+    /// Generation seen at the last check
+    seen: u64,
+    /// When the generation was first seen to differ from `persisted`
+    dirty_since: Option<Instant>,
+    /// When the generation was last seen to change
+    changed_at: Instant,
+}
+
+impl Tracker {
+    /// Starts with `generation` as the one already in the file
+    pub fn new(generation: u64, now: Instant) -> Self {
+        Tracker {
+            persisted: generation,
+            seen: generation,
+            dirty_since: None,
+            changed_at: now,
+        }
+    }
+
+    /// Whether the file should be written now, given its current generation
+    pub fn due(&mut self, generation: u64, now: Instant, policy: &Policy) -> bool {
+        if generation == self.persisted {
+            self.dirty_since = None;
+            return false;
+        }
+
+        if generation != self.seen {
+            self.seen = generation;
+            self.changed_at = now;
+        }
+
+        let dirty_since = *self.dirty_since.get_or_insert(now);
+
+        now.duration_since(self.changed_at) >= policy.debounce
+            || now.duration_since(dirty_since) >= policy.max_latency
+    }
+
+    /// When the changes seen so far become due if nothing else changes, so the persistence
+    /// thread can wake up then instead of at its next regular check
+    pub fn deadline(&self, policy: &Policy) -> Option<Instant> {
+        self.dirty_since
+            .map(|dirty_since| (self.changed_at + policy.debounce).min(dirty_since + policy.max_latency))
+    }
+
+    /// Whether there are changes that haven't been written yet, whatever the policy
+    pub fn dirty(&self, generation: u64) -> bool {
+        generation != self.persisted
+    }
+
+    /// Records that `generation` was written, which must have been read before the registers were
+    pub fn persisted(&mut self, generation: u64) {
+        self.persisted = generation;
+        self.dirty_since = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    type Error = Box<dyn std::error::Error>;
+
+    #[test]
+    pub fn test_policy() -> Result<(), Error> {
+        let policy = Policy {
+            debounce: Duration::from_secs(2),
+            max_latency: Duration::from_secs(5),
+        };
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        let mut tracker = Tracker::new(0, start);
+
+        // nothing changed, nothing to write
+        assert!(!tracker.due(0, at(10), &policy));
+
+        // a single change is written once it has settled
+        assert_eq!(tracker.deadline(&policy), None);
+        assert!(!tracker.due(1, at(10), &policy));
+        assert_eq!(tracker.deadline(&policy), Some(at(12)));
+        assert!(!tracker.due(1, at(11), &policy));
+        assert!(tracker.due(1, at(12), &policy));
+        tracker.persisted(1);
+        assert_eq!(tracker.deadline(&policy), None);
+        assert!(!tracker.due(1, at(13), &policy));
+
+        // a change every second never settles, but is written after the max latency
+        for (generation, secs) in (2..).zip(20..25) {
+            assert!(!tracker.due(generation, at(secs), &policy));
+        }
+        assert_eq!(tracker.deadline(&policy), Some(at(25)));
+        assert!(tracker.due(7, at(25), &policy));
+        tracker.persisted(7);
+
+        // and the latency counts from the first change after that write
+        assert!(!tracker.due(8, at(26), &policy));
+        assert!(!tracker.due(9, at(27), &policy));
+        assert!(tracker.dirty(9));
+        assert!(tracker.due(9, at(29), &policy));
+
+        Ok(())
+    }
+}
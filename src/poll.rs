@@ -0,0 +1,412 @@
+use std::{net::SocketAddr, ops::RangeInclusive, sync::Arc, time::Duration};
+
+use log::{info, warn};
+use serde_json::{Map, Value};
+use tokio::time::{sleep_until, Instant};
+use tokio_modbus::{Request, Response, SlaveRequest};
+
+use crate::{
+    addressing::Addressing,
+    gateway::{Upstream, DEFAULT_TIMEOUT},
+    json::{JsonError, Layout, POLL_KEY},
+    register_manager::{RegisterManager, RegisterType},
+    validation::validate_time,
+};
+
+/// Time between two reads of a block that doesn't set its own interval
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest number of coils or inputs a single read may ask for
+const MAX_BITS: u64 = 2000;
+
+/// Largest number of registers a single read may ask for
+const MAX_WORDS: u64 = 125;
+
+/// A range of a remote device read periodically into the local tables
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// Unit ID of the remote device
+    unit: u8,
+    /// Table read on the remote device, which decides the function code
+    table: RegisterType,
+    /// 0-based address of the first register on the remote device
+    address: u16,
+    count: u16,
+    interval: Duration,
+    /// Local register number the first value is stored at
+    target: u32,
+    /// Local coil set while the block is stale, i.e. its last read failed or none succeeded yet
+    status: Option<u32>,
+}
+
+impl Block {
+    fn from_json(i: usize, value: &Value) -> Result<Self, JsonError> {
+        let invalid = |field: &str| JsonError::Invalid(format!("Invalid value for '{}' of poll block {}", field, i));
+
+        let block = value
+            .as_object()
+            .ok_or_else(|| JsonError::Invalid(format!("Poll block {} must be an object", i)))?;
+
+        if let Some(key) = block.keys().find(|k| !BLOCK_KEYS.contains(&k.as_str())) {
+            return Err(JsonError::Invalid(format!("Unknown setting '{}' in poll block {}", key, i)));
+        }
+
+        let number = |field: &str, default: Option<u64>, range: RangeInclusive<u64>| match block.get(field) {
+            Some(v) => v.as_u64().filter(|n| range.contains(n)).ok_or_else(|| invalid(field)),
+            None => default.ok_or_else(|| JsonError::Invalid(format!("Missing '{}' in poll block {}", field, i))),
+        };
+
+        let unit = number("unit", Some(1), 0..=255)? as u8;
+
+        let (table, max_count) = match number("function", None, 1..=4)? {
+            1 => (RegisterType::Coils, MAX_BITS),
+            2 => (RegisterType::Inputs, MAX_BITS),
+            3 => (RegisterType::HoldingRegisters, MAX_WORDS),
+            _ => (RegisterType::InputRegisters, MAX_WORDS),
+        };
+
+        let address = number("address", None, 0..=u16::MAX as u64)? as u16;
+        let count = number("count", None, 1..=max_count)? as u16;
+        if address as u32 + count as u32 > 0x10000 {
+            return Err(invalid("count"));
+        }
+
+        let interval = match block.get("interval") {
+            Some(v) => v
+                .as_str()
+                .and_then(|s| validate_time(s).ok())
+                .filter(|interval| !interval.is_zero())
+                .ok_or_else(|| invalid("interval"))?,
+            None => DEFAULT_INTERVAL,
+        };
+
+        // bits can only be stored in coils or inputs, and words in registers
+        let target = number("target", Some((table.first() + address as u32) as u64), 1..=465536)? as u32;
+        match RegisterType::locate(target) {
+            Some((target_table, offset))
+                if bits(target_table) == bits(table) && offset as u32 + count as u32 <= 0x10000 => {}
+            _ => return Err(invalid("target")),
+        }
+
+        let status = match block.get("status") {
+            Some(_) => Some(number("status", None, 1..=65536)? as u32),
+            None => None,
+        };
+
+        Ok(Block {
+            unit,
+            table,
+            address,
+            count,
+            interval,
+            target,
+            status,
+        })
+    }
+
+    fn request(&self) -> Request<'static> {
+        match self.table {
+            RegisterType::Coils => Request::ReadCoils(self.address, self.count),
+            RegisterType::Inputs => Request::ReadDiscreteInputs(self.address, self.count),
+            RegisterType::HoldingRegisters => Request::ReadHoldingRegisters(self.address, self.count),
+            RegisterType::InputRegisters => Request::ReadInputRegisters(self.address, self.count),
+        }
+    }
+
+    /// The values of a response to `request`, bits as 0 or 1
+    fn values(&self, response: Response) -> Option<Vec<u16>> {
+        let mut values = match response {
+            Response::ReadCoils(bits) | Response::ReadDiscreteInputs(bits) => {
+                bits.into_iter().map(u16::from).collect()
+            }
+            Response::ReadHoldingRegisters(words) | Response::ReadInputRegisters(words) => words,
+            _ => return None,
+        };
+
+        // bits are padded to whole bytes
+        values.truncate(self.count as usize);
+        Some(values).filter(|v| v.len() == self.count as usize)
+    }
+}
+
+const SECTION_KEYS: [&str; 4] = ["upstream", "timeout", "write_through", "blocks"];
+
+const BLOCK_KEYS: [&str; 7] = ["unit", "function", "address", "count", "interval", "target", "status"];
+
+fn bits(table: RegisterType) -> bool {
+    matches!(table, RegisterType::Coils | RegisterType::Inputs)
+}
+
+/// Mirrors blocks of a remote device into the local tables
+pub struct Poller {
+    upstream: Arc<Upstream>,
+    /// Whether writes to holding registers mirrored from the device are passed on to it
+    write_through: bool,
+    blocks: Vec<Block>,
+}
+
+impl Poller {
+    /// Reads the `poll` section, if the file has one
+    pub fn from_layout(layout: &Layout) -> Result<Option<Self>, JsonError> {
+        let Some(section) = layout.get(POLL_KEY) else {
+            return Ok(None);
+        };
+
+        let invalid = |field: &str| JsonError::Invalid(format!("Invalid value for '{}' of '{}'", field, POLL_KEY));
+
+        let section: &Map<String, Value> = section
+            .as_object()
+            .ok_or_else(|| JsonError::Invalid(format!("'{}' must be an object", POLL_KEY)))?;
+
+        if let Some(key) = section.keys().find(|k| !SECTION_KEYS.contains(&k.as_str())) {
+            return Err(JsonError::Invalid(format!("Unknown setting '{}' in '{}'", key, POLL_KEY)));
+        }
+
+        let upstream = section
+            .get("upstream")
+            .and_then(Value::as_str)
+            .and_then(|s| s.parse::<SocketAddr>().ok())
+            .ok_or_else(|| invalid("upstream"))?;
+
+        let timeout = match section.get("timeout") {
+            Some(v) => v.as_str().and_then(|s| validate_time(s).ok()).ok_or_else(|| invalid("timeout"))?,
+            None => DEFAULT_TIMEOUT,
+        };
+
+        let write_through = match section.get("write_through") {
+            Some(v) => v.as_bool().ok_or_else(|| invalid("write_through"))?,
+            None => false,
+        };
+
+        let blocks = section
+            .get("blocks")
+            .and_then(Value::as_array)
+            .filter(|blocks| !blocks.is_empty())
+            .ok_or_else(|| JsonError::Invalid(format!("'{}' must have at least one block", POLL_KEY)))?
+            .iter()
+            .enumerate()
+            .map(|(i, block)| Block::from_json(i, block))
+            .collect::<Result<Vec<Block>, JsonError>>()?;
+
+        Ok(Some(Poller {
+            upstream: Arc::new(Upstream::new(upstream, timeout)),
+            write_through,
+            blocks,
+        }))
+    }
+
+    /// The write to pass on to the device if write-through is on, and the request writes
+    /// holding registers that lie within a single block mirrored from its holding registers
+    pub fn write_through(&self, addressing: Addressing, req: &Request) -> Option<(Arc<Upstream>, SlaveRequest<'static>)> {
+        if !self.write_through {
+            return None;
+        }
+
+        let (addr, cnt) = match req {
+            Request::WriteSingleRegister(addr, _) | Request::MaskWriteRegister(addr, _, _) => (*addr, 1),
+            Request::WriteMultipleRegisters(addr, values) | Request::ReadWriteMultipleRegisters(_, _, addr, values) => {
+                (*addr, values.len() as u32)
+            }
+            _ => return None,
+        };
+
+        let number = RegisterType::HoldingRegisters.first()
+            + addressing.offset(&RegisterType::HoldingRegisters, addr, cnt as u16).ok()? as u32;
+
+        let block = self.blocks.iter().find(|b| {
+            b.table == RegisterType::HoldingRegisters
+                && RegisterType::locate(b.target).is_some_and(|(t, _)| t == RegisterType::HoldingRegisters)
+                && b.target <= number
+                && number + cnt <= b.target + b.count as u32
+        })?;
+
+        let remote = block.address + (number - block.target) as u16;
+        let request = match req {
+            Request::WriteSingleRegister(_, value) => Request::WriteSingleRegister(remote, *value),
+            Request::MaskWriteRegister(_, and_mask, or_mask) => Request::MaskWriteRegister(remote, *and_mask, *or_mask),
+            // only the write half of a read/write is the device's business, the read is answered locally
+            Request::WriteMultipleRegisters(_, values) | Request::ReadWriteMultipleRegisters(_, _, _, values) => {
+                Request::WriteMultipleRegisters(remote, values.to_vec().into())
+            }
+            _ => return None,
+        };
+
+        Some((self.upstream.clone(), SlaveRequest { slave: block.unit, request }))
+    }
+}
+
+/// Polls the blocks of the manager's `poll` section for as long as the process runs
+pub async fn run(manager: Arc<RegisterManager>) {
+    let Some(poller) = manager.poller() else {
+        return;
+    };
+
+    info!("Polling {} blocks into {}", poller.blocks.len(), manager.path());
+
+    // nothing has been read yet
+    for status in poller.blocks.iter().filter_map(|b| b.status) {
+        manager.store(status, &[1]);
+    }
+
+    let mut due = vec![Instant::now(); poller.blocks.len()];
+
+    loop {
+        let (i, next) = due.iter().copied().enumerate().min_by_key(|(_, due)| *due).unwrap();
+        sleep_until(next).await;
+
+        let block = &poller.blocks[i];
+
+        // a block that fell behind is read once more rather than catching up on every missed read
+        due[i] = (next + block.interval).max(Instant::now());
+
+        let request = SlaveRequest { slave: block.unit, request: block.request() };
+        let values = match poller.upstream.clone().forward(request).await {
+            Ok(Some(response)) => block.values(response),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Polling {:?} of unit {} failed: {:?}", block.request(), block.unit, e);
+                None
+            }
+        };
+
+        if let Some(values) = &values {
+            manager.store(block.target, values);
+        }
+
+        if let Some(status) = block.status {
+            manager.store(status, &[values.is_none() as u16]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::{UnitMap, UnitService, UnknownUnit};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use tokio::{net::TcpListener, test};
+    use tokio_modbus::{
+        server::{
+            tcp::{accept_tcp_connection, Server},
+            Service,
+        },
+        ExceptionCode,
+    };
+    type Error = Box<dyn std::error::Error>;
+
+    #[test]
+    pub async fn poll_test() -> Result<(), Error> {
+        let remote = Arc::new(
+            RegisterManager::from_json(json!({
+                "400001": 11,
+                "400002": 12,
+                "400003": { "value": 13, "access": "ro" },
+                "100001": 1,
+                "100002": 0,
+                "100003": 1,
+            }))
+            .unwrap(),
+        );
+        let remote_map = Arc::new(UnitMap::new(HashMap::from([(2, remote.clone())]), None, UnknownUnit::Exception));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = listener.local_addr()?;
+        tokio::spawn(async move {
+            let new_service = |addr| Ok(Some(UnitService::new(&remote_map, addr, None, None, Addressing::ZeroBased)));
+            let on_connected = |stream, addr| async move { accept_tcp_connection(stream, addr, &new_service) };
+            Server::new(listener).serve(&on_connected, |_| {}).await
+        });
+
+        let manager = Arc::new(
+            RegisterManager::from_json(json!({
+                "400011": 0,
+                "400012": { "value": 0, "max": 100 },
+                "400013": 0,
+                "400015": 0,
+                "poll": {
+                    "upstream": upstream.to_string(),
+                    "write_through": true,
+                    "blocks": [
+                        { "unit": 2, "function": 3, "address": 0, "count": 2, "interval": "20ms", "target": 300001, "status": 1 },
+                        { "unit": 2, "function": 2, "address": 0, "count": 3, "interval": "20ms", "status": 2 },
+                        { "unit": 2, "function": 3, "address": 0, "count": 2, "interval": "20ms", "target": 400011 },
+                        { "unit": 2, "function": 3, "address": 2, "count": 1, "interval": "20ms", "target": 400015 },
+                        { "unit": 9, "function": 4, "address": 0, "count": 1, "interval": "20ms", "status": 3 }
+                    ]
+                }
+            }))
+            .unwrap(),
+        );
+
+        let poller = tokio::spawn(run(manager.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::InputRegisters, 0, 2).unwrap(), [11, 12]);
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::Inputs, 0, 3).unwrap(), [1, 0, 1]);
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 10, 2).unwrap(), [11, 12]);
+
+        // the status coil of the block from a unit the remote doesn't have stays set
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::Coils, 0, 3).unwrap(), [0, 0, 1]);
+
+        // writes within the mirrored holding registers reach the remote device
+        let unit_map = UnitMap::new(HashMap::from([(1, manager.clone())]), None, UnknownUnit::Drop);
+        let service = UnitService::new(&unit_map, "127.0.0.1:1".parse()?, None, None, Addressing::ZeroBased);
+        let write = |request| service.call(SlaveRequest { slave: 1, request });
+
+        assert!(write(Request::WriteMultipleRegisters(10, vec![21, 22].into())).await.is_ok());
+        assert_eq!(remote.read_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 0, 2).unwrap(), [21, 22]);
+
+        // as does the write half of a read/write
+        assert_eq!(
+            write(Request::ReadWriteMultipleRegisters(10, 2, 10, vec![31, 32].into())).await,
+            Ok(Some(Response::ReadWriteMultipleRegisters(vec![31, 32])))
+        );
+        assert_eq!(remote.read_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 0, 2).unwrap(), [31, 32]);
+
+        // writes reaching beyond them only change the local registers
+        assert!(write(Request::WriteMultipleRegisters(11, vec![0, 0].into())).await.is_ok());
+        assert_eq!(remote.read_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 0, 2).unwrap(), [31, 32]);
+
+        // a write the local map rejects is never passed on
+        assert_eq!(write(Request::WriteSingleRegister(11, 500)).await, Err(ExceptionCode::IllegalDataValue));
+        assert_eq!(remote.read_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 1, 1).unwrap(), [32]);
+
+        poller.abort();
+
+        // nor is a write the device rejects applied locally
+        assert_eq!(
+            write(Request::WriteSingleRegister(14, 99)).await,
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 14, 1).unwrap(), [13]);
+
+        let poll = |section: Value| RegisterManager::from_json(json!({ "poll": section })).err().map(|e| e.to_string());
+        let block = |block: Value| poll(json!({ "upstream": "127.0.0.1:502", "blocks": [block] }));
+
+        assert_eq!(poll(json!({ "upstream": "127.0.0.1:502", "blocks": [] })), Some("'poll' must have at least one block".into()));
+        assert_eq!(poll(json!({ "upstream": "localhost", "blocks": [] })), Some("Invalid value for 'upstream' of 'poll'".into()));
+        assert_eq!(
+            block(json!({ "function": 5, "address": 0, "count": 1 })),
+            Some("Invalid value for 'function' of poll block 0".into())
+        );
+        assert_eq!(
+            block(json!({ "function": 3, "address": 0, "count": 126 })),
+            Some("Invalid value for 'count' of poll block 0".into())
+        );
+        assert_eq!(
+            block(json!({ "function": 3, "address": 0, "count": 1, "target": 1 })),
+            Some("Invalid value for 'target' of poll block 0".into())
+        );
+        assert_eq!(
+            block(json!({ "function": 3, "address": 0, "count": 2, "target": 465536 })),
+            Some("Invalid value for 'target' of poll block 0".into())
+        );
+        assert_eq!(
+            block(json!({ "function": 3, "address": 0, "count": 1, "every": "1s" })),
+            Some("Unknown setting 'every' in poll block 0".into())
+        );
+        assert_eq!(block(json!({ "function": 1, "address": 0, "count": 16, "status": 17 })), None);
+
+        Ok(())
+    }
+}
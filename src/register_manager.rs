@@ -1,18 +1,34 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
 };
 
-use log::{error, warn};
+use log::{debug, error, warn};
 use serde_json::Value;
 
-use crate::json::{self, JsonError};
+use crate::{
+    addressing::Addressing,
+    device_id::DeviceIdentification,
+    diagnostics::Counters,
+    poll::Poller,
+    json::{self, Access, JsonError, JsonResult, Layout, Metadata},
+};
 
 pub type Register = HashMap<u16, u16>;
 
+/// Register file used when none is given
+pub const DEFAULT_PATH: &str = "data.json";
+
 #[derive(Debug)]
 pub enum RegisterError {
     OutOfBounds,
+    /// The write touched a read-only or already written write-once register
+    ReadOnly,
+    /// The write would put a typed value outside its min/max
+    OutOfRange,
     FileWriteError,
 }
 
@@ -23,7 +39,17 @@ impl Default for RegisterManager {
             coils: Arc::new(RwLock::new(HashMap::new())),
             holding_registers: Arc::new(RwLock::new(HashMap::new())),
             input_registers: Arc::new(RwLock::new(HashMap::new())),
-            keys: vec![],
+            layout: Layout::new(),
+            metadata: vec![],
+            device_id: None,
+            server_id: json::DEFAULT_SERVER_ID,
+            addressing: None,
+            poller: None,
+            counters: Counters::default(),
+            written: Mutex::new(HashSet::new()),
+            defined: HashSet::new(),
+            generation: AtomicU64::new(0),
+            path: DEFAULT_PATH.into(),
         }
     }
 }
@@ -33,10 +59,27 @@ pub struct RegisterManager {
     coils: Arc<RwLock<Register>>,
     holding_registers: Arc<RwLock<Register>>,
     input_registers: Arc<RwLock<Register>>,
-    keys: Vec<String>,
+    layout: Layout,
+    metadata: Vec<Metadata>,
+    device_id: Option<DeviceIdentification>,
+    server_id: u8,
+    /// Addressing mode of this file, overriding that of the listener
+    addressing: Option<Addressing>,
+    /// Remote device mirrored into the tables, if any
+    poller: Option<Poller>,
+    counters: Counters,
+    /// Keys of write-once registers that have been written since startup
+    written: Mutex<HashSet<String>>,
+    /// 6-digit numbers of the registers defined by the file
+    defined: HashSet<u32>,
+    /// Bumped by every write that changes a register of the file
+    generation: AtomicU64,
+    /// File the registers are persisted to
+    path: String,
 }
 
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum RegisterType {
     Inputs,
     Coils,
@@ -44,6 +87,46 @@ pub enum RegisterType {
     InputRegisters,
 }
 
+impl RegisterType {
+    const ALL: [RegisterType; 4] = [
+        RegisterType::Coils,
+        RegisterType::Inputs,
+        RegisterType::InputRegisters,
+        RegisterType::HoldingRegisters,
+    ];
+
+    /// Table named by a key prefix such as `hr:`
+    pub fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix {
+            "co" => Some(RegisterType::Coils),
+            "di" => Some(RegisterType::Inputs),
+            "ir" => Some(RegisterType::InputRegisters),
+            "hr" => Some(RegisterType::HoldingRegisters),
+            _ => None,
+        }
+    }
+
+    /// Number of the first register of the table in 6-digit notation, e.g. 400001
+    pub fn first(&self) -> u32 {
+        match self {
+            RegisterType::Coils => 1,
+            RegisterType::Inputs => 100001,
+            RegisterType::InputRegisters => 300001,
+            RegisterType::HoldingRegisters => 400001,
+        }
+    }
+
+    /// Table of a 6-digit register number and the offset of the register within it
+    pub fn locate(number: u32) -> Option<(Self, u16)> {
+        Self::ALL.into_iter().find_map(|table| {
+            number
+                .checked_sub(table.first())
+                .and_then(|offset| u16::try_from(offset).ok())
+                .map(|offset| (table, offset))
+        })
+    }
+}
+
 impl std::fmt::Display for RegisterType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -56,6 +139,7 @@ impl std::fmt::Display for RegisterType {
 }
 
 impl RegisterManager {
+    #[allow(dead_code)]
     pub fn new() -> Self {
         RegisterManager {
             // debug,
@@ -64,70 +148,106 @@ impl RegisterManager {
     }
 
     pub fn from_json(json: Value) -> Result<Self, JsonError> {
-        // let JsonResult { coils, holding_registers, .. } = json::parse(json)?;
-        let (registers, keys) = json::parse(json)?;
-
-        let coils = registers
-            .keys()
-            .into_iter()
-            .cloned()
-            .filter(|&key| key >= 1 && key <= 9999)
-            .filter_map(|key| registers.get(&key).and_then(|val| Some((key, val.clone()))))
-            .collect();
+        let JsonResult { registers, layout, metadata } = json::parse(json)?;
+        let device_id = DeviceIdentification::from_layout(&layout)?;
+        let server_id = json::server_id(&layout)?;
+        let addressing = json::addressing(&layout)?;
+        let poller = Poller::from_layout(&layout)?;
+
+        for meta in &metadata {
+            debug!("Defined {}", meta);
+        }
 
-        let inputs = registers
-            .keys()
-            .into_iter()
-            .cloned()
-            .filter(|&key| key >= 10001 && key <= 19999)
-            .filter_map(|key| registers.get(&key).and_then(|val| Some((key, val.clone()))))
-            .collect();
+        let defined = registers.keys().copied().collect();
+        let mut tables: HashMap<RegisterType, Register> = HashMap::new();
 
-        let input_registers = registers
-            .keys()
-            .into_iter()
-            .cloned()
-            .filter(|&key| key >= 30001 && key <= 39999)
-            .filter_map(|key| registers.get(&key).and_then(|val| Some((key, val.clone()))))
-            .collect();
+        for (number, value) in registers {
+            if let Some((table, offset)) = RegisterType::locate(number) {
+                tables.entry(table).or_default().insert(offset, value);
+            }
+        }
 
-        let holding_registers = registers
-            .keys()
-            .into_iter()
-            .cloned()
-            .filter(|&key| key >= 40001 && key <= 49999)
-            .filter_map(|key| registers.get(&key).and_then(|val| Some((key, val.clone()))))
-            .collect();
+        let mut table = |registers_type| tables.remove(&registers_type).unwrap_or_default();
+        let coils = table(RegisterType::Coils);
+        let inputs = table(RegisterType::Inputs);
+        let input_registers = table(RegisterType::InputRegisters);
+        let holding_registers = table(RegisterType::HoldingRegisters);
 
         Ok(RegisterManager {
             coils: Arc::new(RwLock::new(coils)),
             inputs: Arc::new(RwLock::new(inputs)),
             input_registers: Arc::new(RwLock::new(input_registers)),
             holding_registers: Arc::new(RwLock::new(holding_registers)),
-            keys,
+            layout,
+            metadata,
+            device_id,
+            server_id,
+            addressing,
+            poller,
+            counters: Counters::default(),
+            written: Mutex::new(HashSet::new()),
+            defined,
+            generation: AtomicU64::new(0),
+            path: DEFAULT_PATH.into(),
         })
     }
 
-    pub fn update_persistence(&self) -> Result<(), RegisterError> {
-        let coils = self.coils.read().unwrap().clone();
-        let inputs = self.inputs.read().unwrap().clone();
-        let input_registers = self.input_registers.read().unwrap().clone();
-        let holding_registers = self.holding_registers.read().unwrap().clone();
+    /// Loads the registers from a json file, which they are also persisted to
+    pub fn load(path: &str) -> Result<Self, JsonError> {
+        json::load(path)
+            .and_then(RegisterManager::from_json)
+            .map(|manager| RegisterManager {
+                path: path.into(),
+                ..manager
+            })
+    }
 
-        let registers: HashMap<u16, u16> = coils
-            .into_iter()
-            .chain(inputs.into_iter())
-            .chain(input_registers.into_iter())
-            .chain(holding_registers.into_iter())
-            .collect();
+    pub fn path(&self) -> &str {
+        &self.path
+    }
 
-        let value = json::registers_to_object(&registers, self.keys.clone()).unwrap();
+    /// Writes the registers back to their file, keeping `backups` previous versions of it
+    pub fn update_persistence(&self, backups: usize) -> Result<(), RegisterError> {
+        let mut registers: HashMap<u32, u16> = HashMap::new();
 
-        if let Err(e) = json::write(value, "data.json") {
-            error!("Error updating persistence: {:?}", e);
+        for registers_type in RegisterType::ALL {
+            let table = self.register_select(registers_type).read().unwrap().clone();
+            let first = registers_type.first();
+
+            registers.extend(table.into_iter().map(|(offset, value)| (first + offset as u32, value)));
         }
 
-        Ok(())
+        let value = json::registers_to_object(&registers, &self.layout).map_err(|e| {
+            error!("Error updating persistence: {:?}", e);
+            RegisterError::FileWriteError
+        })?;
+
+        json::write(value, &self.path, backups).map_err(|e| {
+            error!("Error updating persistence: {:?}", e);
+            RegisterError::FileWriteError
+        })
+    }
+
+    /// Objects answered to Read Device Identification, if the file defines them
+    pub fn device_id(&self) -> Option<&DeviceIdentification> {
+        self.device_id.as_ref()
+    }
+
+    /// ID answered to Report Server ID
+    pub fn server_id(&self) -> u8 {
+        self.server_id
+    }
+
+    pub fn addressing(&self) -> Option<Addressing> {
+        self.addressing
+    }
+
+    pub fn poller(&self) -> Option<&Poller> {
+        self.poller.as_ref()
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
     }
 
     fn register_select(&self, registers_type: RegisterType) -> &Arc<RwLock<Register>> {
@@ -141,66 +261,238 @@ impl RegisterManager {
 
     pub fn read_register(
         &self,
+        addressing: Addressing,
         registers_type: RegisterType,
         addr: u16,
         cnt: u16,
     ) -> Result<Vec<u16>, RegisterError> {
+        let addr = addressing.offset(&registers_type, addr, cnt)?;
+        let registers = self.register_select(registers_type).read().unwrap();
 
-        let addr = match registers_type {
-            RegisterType::Coils => Some(addr).filter(|&a| a >= 1 && a <= 9999),
-            RegisterType::Inputs => Some(addr).filter(|&a| a >= 10001 && a <= 19999),
-            RegisterType::InputRegisters => Some(addr).filter(|&a| a >= 30001 && a <= 39999),
-            RegisterType::HoldingRegisters => Some(addr).filter(|&a| a >= 40001 && a <= 49999),
-        }.ok_or(RegisterError::OutOfBounds)?;
-        
-        let mut response: Vec<u16> = Vec::with_capacity(cnt.into());
+        Self::read_locked(&registers, addr, cnt)
+    }
+
+    pub fn write_register(
+        &self,
+        addressing: Addressing,
+        registers_type: RegisterType,
+        addr: u16,
+        values: &[u16],
+    ) -> Result<(), RegisterError> {
+        let addr = addressing.offset(&registers_type, addr, values.len() as u16)?;
+        let mut registers = self.register_select(registers_type).write().unwrap();
+
+        self.write_locked(registers_type, &mut registers, addr, values)
+    }
+
+    /// Applies `(current & and_mask) | (or_mask & !and_mask)` to a holding register
+    pub fn mask_write_register(
+        &self,
+        addressing: Addressing,
+        addr: u16,
+        and_mask: u16,
+        or_mask: u16,
+    ) -> Result<(), RegisterError> {
+        let addr = addressing.offset(&RegisterType::HoldingRegisters, addr, 1)?;
+        let mut registers = self.holding_registers.write().unwrap();
+
+        let current = Self::read_locked(&registers, addr, 1)?[0];
+        let value = (current & and_mask) | (or_mask & !and_mask);
+
+        self.write_locked(RegisterType::HoldingRegisters, &mut registers, addr, &[value])
+    }
+
+    /// Writes holding registers and reads them back as one operation, the write going first
+    pub fn read_write_registers(
+        &self,
+        addressing: Addressing,
+        read_addr: u16,
+        cnt: u16,
+        write_addr: u16,
+        values: &[u16],
+    ) -> Result<Vec<u16>, RegisterError> {
+        let read_addr = addressing.offset(&RegisterType::HoldingRegisters, read_addr, cnt)?;
+        let write_addr = addressing.offset(&RegisterType::HoldingRegisters, write_addr, values.len() as u16)?;
+        let mut registers = self.holding_registers.write().unwrap();
+
+        // check the read range before writing so a bad read doesn't leave the write applied
+        Self::read_locked(&registers, read_addr, cnt)?;
+        self.write_locked(RegisterType::HoldingRegisters, &mut registers, write_addr, values)?;
+
+        Self::read_locked(&registers, read_addr, cnt)
+    }
 
-        {
-            let registers = self.register_select(registers_type).read().unwrap();
+    fn read_locked(registers: &Register, addr: u16, cnt: u16) -> Result<Vec<u16>, RegisterError> {
+        let mut response: Vec<u16> = Vec::with_capacity(cnt.into());
 
-            for i in 0..cnt {
-                if let Some(value) = registers.get(&(addr + i)) {
-                    response.push(*value);
-                } else {
-                    warn!("Got register out of bounds at {}", &(addr + cnt));
-                    return Err(RegisterError::OutOfBounds);
-                }
+        for i in 0..cnt {
+            if let Some(value) = addr.checked_add(i).and_then(|a| registers.get(&a)) {
+                response.push(*value);
+            } else {
+                warn!("Got register out of bounds at {}", addr as u32 + cnt as u32);
+                return Err(RegisterError::OutOfBounds);
             }
         }
 
         Ok(response)
     }
 
-    pub fn write_register(
+    /// Checks a write the way `write_register` would, without applying it
+    pub fn check_write(
+        &self,
+        addressing: Addressing,
+        registers_type: RegisterType,
+        addr: u16,
+        values: &[u16],
+    ) -> Result<(), RegisterError> {
+        let addr = addressing.offset(&registers_type, addr, values.len() as u16)?;
+        let registers = self.register_select(registers_type).read().unwrap();
+        let written = self.written.lock().unwrap();
+
+        self.validate_locked(registers_type, &registers, &written, addr, values).map(|_| ())
+    }
+
+    /// Validates a write while the caller holds the table's guard and the write-once lock,
+    /// giving the addresses written and the metadata of the keys they touch
+    fn validate_locked(
+        &self,
+        registers_type: RegisterType,
+        registers: &Register,
+        written: &HashSet<String>,
+        addr: u16,
+        values: &[u16],
+    ) -> Result<(Vec<u16>, Vec<&Metadata>), RegisterError> {
+        let addresses = (addr as u32..addr as u32 + values.len() as u32)
+            .map(|a| u16::try_from(a).map_err(|_| RegisterError::OutOfBounds))
+            .collect::<Result<Vec<u16>, RegisterError>>()?;
+
+        // metadata is keyed by 6-digit register numbers
+        let first = registers_type.first();
+
+        let touched: Vec<&Metadata> = self
+            .metadata
+            .iter()
+            .filter(|m| addresses.iter().any(|&a| m.contains(first + a as u32)))
+            .collect();
+
+        // validate the whole range first so a rejected write changes nothing
+        if let Some(reg_addr) = addresses.iter().find(|a| !registers.contains_key(a)) {
+            warn!("Got register out of bounds at {}", reg_addr);
+            return Err(RegisterError::OutOfBounds);
+        }
+
+        for meta in &touched {
+            let rejected = match meta.access {
+                Access::ReadOnly => true,
+                Access::WriteOnce => written.contains(&meta.key),
+                Access::ReadWrite => false,
+            };
+
+            if rejected {
+                warn!("Rejected write to {}", meta);
+                return Err(RegisterError::ReadOnly);
+            }
+
+            // the key as it would be after the write, including registers outside of it
+            let words: Vec<u16> = (meta.address - first..meta.address - first + meta.len)
+                .map(|a| a as u16)
+                .map(|a| match addresses.iter().position(|&w| w == a) {
+                    Some(i) => values[i],
+                    None => registers.get(&a).copied().unwrap_or_default(),
+                })
+                .collect();
+
+            if !meta.in_range(&words) {
+                warn!("Rejected out of range write to {}", meta);
+                return Err(RegisterError::OutOfRange);
+            }
+
+            if !meta.named_bits_only(&words) {
+                warn!("Rejected write of unnamed bits to {}", meta);
+                return Err(RegisterError::OutOfRange);
+            }
+        }
+
+        Ok((addresses, touched))
+    }
+
+    /// Validates and applies a write while the caller holds the table's write guard
+    fn write_locked(
         &self,
         registers_type: RegisterType,
+        registers: &mut Register,
         addr: u16,
         values: &[u16],
     ) -> Result<(), RegisterError> {
-        {
-            let mut registers = self.register_select(registers_type).write().unwrap();
-
-            for (i, value) in values.iter().enumerate() {
-                let reg_addr = addr + i as u16;
-
-                if let Some(val) = registers.get_mut(&reg_addr) {
-                    *val = *value;
-                } else {
-                    warn!("Got register out of bounds at {}", &reg_addr);
-                    return Err(RegisterError::OutOfBounds);
-                }
+        let touched = {
+            let mut written = self.written.lock().unwrap();
+            let (addresses, touched) = self.validate_locked(registers_type, registers, &written, addr, values)?;
+
+            let mut changed = false;
+            for (reg_addr, value) in addresses.iter().zip(values) {
+                changed |= registers.insert(*reg_addr, *value) != Some(*value);
             }
+
+            if changed {
+                self.generation.fetch_add(1, Ordering::SeqCst);
+            }
+
+            for meta in touched.iter().filter(|m| m.access == Access::WriteOnce) {
+                written.insert(meta.key.clone());
+            }
+
+            touched
+        };
+
+        for meta in &touched {
+            debug!("Wrote {}", meta);
         }
 
         Ok(())
     }
+
+    /// Stores values read from a polled device at a 6-digit register number, creating the
+    /// registers that don't exist yet and regardless of their access
+    pub fn store(&self, number: u32, values: &[u16]) {
+        let Some((registers_type, offset)) = RegisterType::locate(number) else {
+            return;
+        };
+
+        let first = registers_type.first();
+        let mut registers = self.register_select(registers_type).write().unwrap();
+
+        // only registers the file defines are persisted, so only they make it dirty
+        let mut changed = false;
+        for (reg_addr, value) in (offset..=u16::MAX).zip(values) {
+            changed |= registers.insert(reg_addr, *value) != Some(*value) && self.defined.contains(&(first + reg_addr as u32));
+        }
+
+        if changed {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Counts the changes to registers that are persisted, see `persistence::Tracker`
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Metadata of the object-form definition covering `addr`, in 6-digit notation, if any
+    #[allow(dead_code)]
+    pub fn metadata(&self, addr: u32) -> Option<&Metadata> {
+        self.metadata.iter().find(|m| m.contains(addr))
+    }
 }
 
 #[cfg(test)]
 mod register_tests {
     use serde_json::json;
 
-    use crate::register_manager::RegisterManager;
+    use crate::{
+        addressing::Addressing,
+        json::Access,
+        register_manager::{RegisterManager, RegisterType},
+    };
     type Error = Box<dyn std::error::Error>;
 
     #[test]
@@ -221,7 +513,143 @@ mod register_tests {
         });
 
         let _ = RegisterManager::from_json(data).unwrap();
-        assert!(true);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_bits() -> Result<(), Error> {
+        let data = json!({
+            "40010": {
+                "type": "bits",
+                "bits": { "0": "running", "3": "fault" },
+                "value": { "running": true, "fault": true },
+            },
+        });
+
+        let manager = RegisterManager::from_json(data).unwrap();
+
+        assert_eq!(manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40010, 1).unwrap(), [0x0009]);
+
+        // only named bits can be written, the others wouldn't be persisted
+        manager.write_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40010, &[0x0001]).unwrap();
+        assert!(manager.write_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40010, &[0x0003]).is_err());
+        assert_eq!(manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40010, 1).unwrap(), [0x0001]);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_metadata() -> Result<(), Error> {
+        let data = json!({
+            "40001/I": { "value": 5, "name": "energy_total", "unit": "Wh", "access": "ro" },
+            "40003": 1,
+        });
+
+        let manager = RegisterManager::from_json(data).unwrap();
+        let meta = manager.metadata(400002).unwrap();
+
+        assert_eq!(meta.key, "40001/I");
+        assert_eq!(meta.name.as_deref(), Some("energy_total"));
+        assert_eq!(meta.unit.as_deref(), Some("Wh"));
+        assert_eq!(meta.access, Access::ReadOnly);
+        assert!(manager.metadata(400003).is_none());
+        assert_eq!(manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40001, 2).unwrap(), [0, 5]);
+
+        assert!(RegisterManager::from_json(json!({ "40001": { "value": 1, "access": "x" } })).is_err());
+        assert!(RegisterManager::from_json(json!({ "40001": { "value": 1, "unit": 1 } })).is_err());
+        assert!(RegisterManager::from_json(json!({
+            "40001": { "value": 1, "name": "a" },
+            "40002": { "value": 1, "name": "a" },
+        })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_mask_and_read_write() -> Result<(), Error> {
+        let data = json!({
+            "40001": 0x12,
+            "40002": 0,
+            "40003": 7,
+            "40010": { "value": 1, "access": "ro" },
+        });
+
+        let manager = RegisterManager::from_json(data).unwrap();
+
+        // example from the Modbus application protocol specification
+        manager.mask_write_register(Addressing::Legacy, 40001, 0xF2, 0x25).unwrap();
+        assert_eq!(manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40001, 1).unwrap(), [0x17]);
+
+        assert_eq!(manager.read_write_registers(Addressing::Legacy, 40001, 3, 40002, &[5, 6]).unwrap(), [0x17, 5, 6]);
+
+        // a failed read or write leaves everything as it was
+        assert!(manager.read_write_registers(Addressing::Legacy, 40002, 4, 40002, &[8]).is_err());
+        assert!(manager.read_write_registers(Addressing::Legacy, 40001, 1, 40003, &[8, 8]).is_err());
+        assert!(manager.mask_write_register(Addressing::Legacy, 40010, 0, 0).is_err());
+        assert_eq!(manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40001, 3).unwrap(), [0x17, 5, 6]);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_generation() -> Result<(), Error> {
+        let manager = RegisterManager::from_json(json!({ "40001": 1, "40002": { "value": 2, "access": "ro" } })).unwrap();
+        assert_eq!(manager.generation(), 0);
+
+        manager.write_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40001, &[5]).unwrap();
+        assert_eq!(manager.generation(), 1);
+
+        // writing the same value again, or being rejected, changes nothing
+        manager.write_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40001, &[5]).unwrap();
+        assert!(manager.write_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40002, &[5]).is_err());
+        assert_eq!(manager.generation(), 1);
+
+        // polled values only count for registers of the file
+        manager.store(400002, &[3, 4]);
+        assert_eq!(manager.generation(), 2);
+        manager.store(400003, &[6]);
+        assert_eq!(manager.generation(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_address_space() -> Result<(), Error> {
+        let data = json!({
+            "40001": 1,
+            "49999": 5,
+            "410000": 2,
+            "hr:65535": 3,
+            "ir:70/I": 4,
+            "065536": 1,
+            "di:0": 1,
+        });
+
+        let manager = RegisterManager::from_json(data).unwrap();
+
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 0, 1).unwrap(), [1]);
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 9999, 1).unwrap(), [2]);
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 65535, 1).unwrap(), [3]);
+        assert!(manager.read_register(Addressing::OneBased, RegisterType::HoldingRegisters, 65535, 2).is_err());
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::InputRegisters, 70, 2).unwrap(), [0, 4]);
+        assert_eq!(manager.read_register(Addressing::Legacy, RegisterType::InputRegisters, 30071, 2).unwrap(), [0, 4]);
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::Coils, 65535, 1).unwrap(), [1]);
+        assert_eq!(manager.read_register(Addressing::Legacy, RegisterType::Inputs, 10001, 1).unwrap(), [1]);
+
+        // legacy addresses can't reach past the last 5-digit key, however many registers they span
+        assert_eq!(manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 49999, 1).unwrap(), [5]);
+        assert!(manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 49999, 2).is_err());
+        assert!(manager.write_register(Addressing::Legacy, RegisterType::HoldingRegisters, 49999, &[6, 7]).is_err());
+
+        manager.write_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 65535, &[30]).unwrap();
+        assert_eq!(manager.read_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 65535, 1).unwrap(), [30]);
+        assert!(manager.write_register(Addressing::ZeroBased, RegisterType::HoldingRegisters, 65535, &[30, 31]).is_err());
+
+        // the same register in two notations
+        assert!(RegisterManager::from_json(json!({ "40001": 1, "hr:0": 2 })).is_err());
+        assert!(RegisterManager::from_json(json!({ "400001/I": 1, "40002": 2 })).is_err());
+        assert!(RegisterManager::from_json(json!({ "hr:65535/I": 1 })).is_err());
 
         Ok(())
     }
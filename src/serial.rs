@@ -0,0 +1,206 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{debug, info};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    time::timeout,
+};
+use tokio_modbus::bytes::BytesMut;
+use tokio_serial::{DataBits, SerialPortBuilderExt, StopBits};
+
+use crate::{
+    addressing::Addressing,
+    framing::{self, Framing, MAX_RTU_LEN},
+    units::{UnitMap, UnitService},
+};
+
+/// Parity of the serial line
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum Parity {
+    None,
+    #[default]
+    Even,
+    Odd,
+}
+
+impl From<Parity> for tokio_serial::Parity {
+    fn from(value: Parity) -> Self {
+        match value {
+            Parity::None => tokio_serial::Parity::None,
+            Parity::Even => tokio_serial::Parity::Even,
+            Parity::Odd => tokio_serial::Parity::Odd,
+        }
+    }
+}
+
+/// Shortest silence ending a frame by default, to allow for the latency of USB adapters
+const MIN_FRAME_GAP: Duration = Duration::from_millis(20);
+
+pub struct SerialConfig {
+    pub path: String,
+    pub baud_rate: u32,
+    pub parity: Parity,
+    pub stop_bits: u8,
+    /// Unit ID the default register map answers to on the line
+    pub slave_id: u8,
+    /// Silence ending a frame, 3.5 character times if not given
+    pub frame_gap: Option<Duration>,
+    pub addressing: Addressing,
+}
+
+impl SerialConfig {
+    /// 3.5 character times of 11 bits, but no less than `MIN_FRAME_GAP`
+    fn frame_gap(&self) -> Duration {
+        self.frame_gap.unwrap_or_else(|| {
+            Duration::from_secs_f64(3.5 * 11.0 / self.baud_rate as f64).max(MIN_FRAME_GAP)
+        })
+    }
+}
+
+/// Serves the units of `unit_map` as an RTU slave on a serial line
+pub async fn serve(config: &SerialConfig, unit_map: &UnitMap) -> std::io::Result<()> {
+    let stream = tokio_serial::new(&config.path, config.baud_rate)
+        .data_bits(DataBits::Eight)
+        .parity(config.parity.into())
+        .stop_bits(match config.stop_bits {
+            2 => StopBits::Two,
+            _ => StopBits::One,
+        })
+        .open_native_async()?;
+
+    info!(
+        "Serial RTU slave {} on {} at {} baud, {:?} parity, {} stop bits",
+        config.slave_id, config.path, config.baud_rate, config.parity, config.stop_bits
+    );
+
+    // other devices share the line, so requests for unknown units are never answered
+    let unit_map = Arc::new(unit_map.on_bus(config.slave_id));
+    let service = UnitService::new(&unit_map, "0.0.0.0:0".parse().unwrap(), None, None, config.addressing);
+
+    process(stream, config.frame_gap(), &unit_map, service).await
+}
+
+/// Answers the frames on the line until it is closed. Unlike RTU over TCP, a frame
+/// only ends with `gap` of silence, as the line also carries the responses of other
+/// devices, which can't be sized by the request formats.
+async fn process<S>(mut stream: S, gap: Duration, unit_map: &UnitMap, service: UnitService) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(MAX_RTU_LEN);
+
+    loop {
+        let read = if buf.is_empty() {
+            stream.read_buf(&mut buf).await?
+        } else {
+            match timeout(gap, stream.read_buf(&mut buf)).await {
+                Ok(read) => read?,
+                Err(_) => {
+                    match Framing::Rtu.flush(&mut buf) {
+                        // requests for the other devices and their responses are none of ours
+                        Some(Ok((slave, _))) if slave != 0 && !unit_map.serves(slave) => {
+                            debug!("Skipped frame for unit {}", slave)
+                        }
+                        Some(frame) => framing::reply(&mut stream, Framing::Rtu, frame, unit_map, &service).await?,
+                        None => {}
+                    }
+                    continue;
+                }
+            }
+        };
+
+        if read == 0 {
+            return Ok(());
+        }
+
+        // noise without a gap is dropped rather than buffered without bound
+        if buf.len() > MAX_RTU_LEN {
+            if let Some(frame) = Framing::Rtu.flush(&mut buf) {
+                framing::reply(&mut stream, Framing::Rtu, frame, unit_map, &service).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register_manager::RegisterManager;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        test,
+    };
+    use crate::units::UnknownUnit;
+    type Error = Box<dyn std::error::Error>;
+
+    fn crc(frame: &[u8]) -> Vec<u8> {
+        let mut frame = frame.to_vec();
+        frame.extend_from_slice(&framing::crc16(&frame).to_le_bytes());
+        frame
+    }
+
+    #[test]
+    pub async fn serial_test() -> Result<(), Error> {
+        let manager = Arc::new(RegisterManager::from_json(json!({ "40001": 7 })).unwrap());
+        let unit_map = UnitMap::new(HashMap::new(), Some(manager), UnknownUnit::Default).on_bus(5);
+        let service = UnitService::new(&unit_map, "0.0.0.0:0".parse().unwrap(), None, None, Addressing::ZeroBased);
+
+        let (mut master, slave) = duplex(256);
+        let gap = Duration::from_millis(10);
+        let server = tokio::spawn(async move { process(slave, gap, &unit_map, service).await });
+
+        // requests for another device on the line and its responses go unanswered,
+        // even those that don't have the length of a request with their function code
+        for frame in [
+            crc(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]),
+            crc(&[0x01, 0x03, 0x02, 0x00, 0x07]),
+            crc(&[0x01, 0x10, 0x00, 0x00, 0x00, 0x02, 0x04, 0x00, 0x01, 0x00, 0x02]),
+            crc(&[0x01, 0x10, 0x00, 0x00, 0x00, 0x02]),
+            crc(&[0x05, 0x03, 0x00, 0x00, 0x00, 0x01]),
+        ] {
+            master.write_all(&frame).await?;
+            tokio::time::sleep(gap * 3).await;
+        }
+
+        let mut response = [0u8; 7];
+        master.read_exact(&mut response).await?;
+        assert_eq!(response.to_vec(), crc(&[0x05, 0x03, 0x02, 0x00, 0x07]));
+
+        // and aren't bus errors
+        let bus_errors = crc(&[0x05, 0x08, 0x00, 0x0C, 0x00, 0x00]);
+        master.write_all(&bus_errors).await?;
+
+        let mut response = [0u8; 8];
+        master.read_exact(&mut response).await?;
+        assert_eq!(response.to_vec(), crc(&[0x05, 0x08, 0x00, 0x0C, 0x00, 0x00]));
+
+        // a frame cut short is flushed after the gap and counted as a bus error
+        master.write_all(&[0x05, 0x03, 0x00]).await?;
+        tokio::time::sleep(gap * 3).await;
+        master.write_all(&bus_errors).await?;
+
+        master.read_exact(&mut response).await?;
+        assert_eq!(response.to_vec(), crc(&[0x05, 0x08, 0x00, 0x0C, 0x00, 0x01]));
+
+        drop(master);
+        server.await??;
+
+        assert_eq!(
+            SerialConfig {
+                path: "/dev/null".into(),
+                baud_rate: 1200,
+                parity: Parity::Even,
+                stop_bits: 1,
+                slave_id: 1,
+                frame_gap: None,
+                addressing: Addressing::Legacy,
+            }
+            .frame_gap(),
+            Duration::from_secs_f64(3.5 * 11.0 / 1200.0)
+        );
+
+        Ok(())
+    }
+}
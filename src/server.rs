@@ -1,45 +1,107 @@
+use std::collections::HashMap;
+use std::sync::mpsc::RecvTimeoutError;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{net::SocketAddr, sync::Arc};
 use ipnetwork::IpNetwork;
 use log::{error, info};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, UdpSocket};
 
 use tokio_modbus::server::tcp::{accept_tcp_connection, Server};
-use crate::json;
-use crate::register_manager::RegisterManager;
-use crate::service::ModbusService;
+use crate::addressing::Addressing;
+use crate::framing::{self, Framing};
+use crate::gateway::{Gateway, Route};
+use crate::persistence::{Policy, Tracker};
+use crate::poll;
+use crate::serial::{self, SerialConfig};
+use crate::tls::{self, TlsConfig};
+use crate::udp;
+use crate::register_manager::{RegisterManager, DEFAULT_PATH};
+use crate::units::{UnitMap, UnitService, UnknownUnit};
 
 
 pub struct ServerConfig {
     pub socket_addr: SocketAddr,
     pub update_frequency: Duration,
+    /// Previous versions kept of each register file
+    pub backups: usize,
+    /// When to write register files back after a change
+    pub persistence: Policy,
     pub read_whitelist: Option<Vec<IpNetwork>>,
     pub write_whitelist: Option<Vec<IpNetwork>>,
+    pub units: Vec<(u8, String)>,
+    pub unknown_unit: UnknownUnit,
+    /// Upstream servers for units without a register file
+    pub routes: Vec<Route>,
+    pub addressing: Addressing,
+    pub framing: Framing,
+    /// Whether to serve `socket_addr` at all, false when only the serial line is served
+    pub tcp: bool,
+    /// Address of the Modbus/UDP socket, if any
+    pub udp: Option<SocketAddr>,
+    /// Modbus/TCP Security listener, if any
+    pub tls: Option<TlsConfig>,
+    pub serial: Option<SerialConfig>,
+}
+
+fn load_manager(path: &str) -> Result<Arc<RegisterManager>, Box<dyn std::error::Error>> {
+    match RegisterManager::load(path) {
+        Ok(v) => Ok(Arc::new(v)),
+        Err(e) => {
+            error!("Failed to loading json {path}: {e}");
+            Err("Failed to load json".into())
+        }
+    }
 }
 
 pub async fn server_context(config: ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Server listening on {}", config.socket_addr);
+    let listener = match config.tcp {
+        true => {
+            info!("Server listening on {} ({:?} framing)", config.socket_addr, config.framing);
+            Some(TcpListener::bind(config.socket_addr).await?)
+        }
+        false => None,
+    };
 
-    let listener = TcpListener::bind(config.socket_addr).await?;
+    let tls_listener = match &config.tls {
+        Some(tls) => {
+            info!("TLS listening on {}", tls.socket_addr);
+            Some((TcpListener::bind(tls.socket_addr).await?, tls.acceptor()?))
+        }
+        None => None,
+    };
 
-    let manager = Arc::new(
-        match json::load("data.json")
-            .and_then(|v| RegisterManager::from_json(v)) {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("Failed to loading json: {e}");
-                    return Err("Failed to load json".into())
-                }
-            }
-    );
+    let socket = match config.udp {
+        Some(addr) => Some(UdpSocket::bind(addr).await?),
+        None => None,
+    };
 
-    let server = Server::new(listener);
+    let mut units = HashMap::new();
+    for (id, path) in &config.units {
+        info!("Serving unit {id} from {path}");
+        if units.insert(*id, load_manager(path)?).is_some() {
+            return Err(format!("Unit {id} is defined more than once").into());
+        }
+    }
 
-    let new_service = |addr: SocketAddr| {
-        Ok(Some(ModbusService::new(manager.clone(), addr, config.read_whitelist.clone(), config.write_whitelist.clone())))
+    // the default map serves every unit when no units are given
+    let default = match (config.units.is_empty(), config.unknown_unit) {
+        (true, _) | (false, UnknownUnit::Default) => Some(load_manager(DEFAULT_PATH)?),
+        _ => None,
     };
 
+    for route in &config.routes {
+        info!("Routing {:?}", route);
+    }
+
+    let unit_map = Arc::new(UnitMap::new(units, default, config.unknown_unit).with_gateway(Gateway::new(config.routes.clone())));
+
+    let unit_service = |addr: SocketAddr| {
+        UnitService::new(&unit_map, addr, config.read_whitelist.clone(), config.write_whitelist.clone(), config.addressing)
+    };
+
+    let new_service = |addr: SocketAddr| Ok(Some(unit_service(addr)));
+
     let on_connected = |stream, socket_addr: SocketAddr| async move {
         accept_tcp_connection(stream, socket_addr, &new_service)
     };
@@ -48,27 +110,94 @@ pub async fn server_context(config: ServerConfig) -> Result<(), Box<dyn std::err
         error!("{err}");
     };    
 
-    new_service(config.socket_addr)?;
+    for manager in unit_map.managers().filter(|m| m.poller().is_some()) {
+        tokio::spawn(poll::run(manager.clone()));
+    }
 
-    let persistence_clone = manager.clone();
+    let persistence_clone = unit_map.clone();
     let (tx_stop, rx_stop) = std::sync::mpsc::channel::<()>();
 
     let persistence_thread = thread::spawn(move || {
+        let mut files: Vec<(Arc<RegisterManager>, Tracker)> = persistence_clone
+            .managers()
+            .map(|manager| (manager.clone(), Tracker::new(manager.generation(), Instant::now())))
+            .collect();
+
         loop {
-            if rx_stop.try_recv().is_ok() {
-                break;
+            // wake up early for changes that become due before the next check, but not for those
+            // already due, which are only left over after a failed write
+            let now = Instant::now();
+            let timeout = files
+                .iter()
+                .filter_map(|(_, tracker)| tracker.deadline(&config.persistence))
+                .filter(|deadline| *deadline > now)
+                .map(|deadline| deadline - now)
+                .fold(config.update_frequency, Duration::min);
+
+            // whatever hasn't been written yet is written on the way out
+            let stopping = !matches!(rx_stop.recv_timeout(timeout), Err(RecvTimeoutError::Timeout));
+
+            for (manager, tracker) in &mut files {
+                // read before the registers are, so a change made meanwhile is written next time
+                let generation = manager.generation();
+
+                let due = match stopping {
+                    true => tracker.dirty(generation),
+                    false => tracker.due(generation, Instant::now(), &config.persistence),
+                };
+
+                if !due {
+                    continue;
+                }
+
+                match manager.update_persistence(config.backups) {
+                    Ok(()) => tracker.persisted(generation),
+                    Err(e) => error!("Error updating persistence of {}: {:?}", manager.path(), e),
+                }
             }
 
-            thread::sleep(config.update_frequency);
-            if let Err(e) = persistence_clone.update_persistence() {
-                error!("Error updating persistence: {:?}", e);
+            if stopping {
+                break;
             }
         }
     });
     
-    server.serve(&on_connected, on_process_error).await?;
+    let tcp = async {
+        match (listener, config.framing) {
+            (None, _) => Ok(()),
+            (Some(listener), Framing::Tcp) => Server::new(listener).serve(&on_connected, on_process_error).await,
+            (Some(listener), framing) => framing::serve(listener, framing, unit_map.clone(), unit_service).await,
+        }
+    };
+
+    let udp = async {
+        match socket {
+            Some(socket) => udp::serve(socket, unit_map.clone(), unit_service).await,
+            None => Ok(()),
+        }
+    };
+
+    let tls = async {
+        match (tls_listener, &config.tls) {
+            (Some((listener, acceptor)), Some(tls)) => tls::serve(listener, acceptor, &tls.roles, unit_map.clone(), unit_service).await,
+            _ => Ok(()),
+        }
+    };
+
+    let serial = async {
+        match &config.serial {
+            Some(serial) => serial::serve(serial, &unit_map).await,
+            None => Ok(()),
+        }
+    };
+
+    // runs until either listener fails
+    let result = tokio::try_join!(tcp, udp, tls, serial);
+
     tx_stop.send(()).unwrap();
     persistence_thread.join().unwrap();
 
+    result?;
+
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,36 +1,158 @@
+use crate::{addressing::Addressing, device_id, diagnostics, gateway::Upstream};
 use crate::register_manager::{RegisterError, RegisterManager, RegisterType};
-use ipnetwork::IpNetwork;
+use crate::validation::{Permissions, Whitelist};
 use log::{debug, error, warn};
 use std::{future, net::SocketAddr, sync::Arc};
-use tokio_modbus::{ExceptionCode, Request, Response};
+use tokio_modbus::{ExceptionCode, Request, Response, SlaveRequest};
 
+/// Who sends the requests of a connection, and what they may do
+#[derive(Clone)]
+pub struct Access {
+    ip: SocketAddr,
+    read_whitelist: Whitelist,
+    write_whitelist: Whitelist,
+    /// Granted by the certificate of a TLS client, in place of the whitelists
+    permissions: Option<Permissions>,
+}
+
+impl Access {
+    pub fn new(ip: SocketAddr, read_whitelist: Whitelist, write_whitelist: Whitelist) -> Self {
+        Access {
+            ip,
+            read_whitelist,
+            write_whitelist,
+            permissions: None,
+        }
+    }
+
+    pub fn with_permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = Some(permissions);
+        self
+    }
+
+    pub fn ip(&self) -> SocketAddr {
+        self.ip
+    }
+
+    /// Checks the request against the whitelists or the role of the client, giving the
+    /// exception to answer with if it isn't allowed
+    pub fn authorize(&self, req: &Request) -> Result<(), ExceptionCode> {
+        let write = match req {
+            Request::WriteMultipleCoils(_, _)
+            | Request::WriteSingleCoil(_, _)
+            | Request::WriteMultipleRegisters(_, _)
+            | Request::WriteSingleRegister(_, _)
+            | Request::MaskWriteRegister(_, _, _)
+            | Request::ReadWriteMultipleRegisters(_, _, _, _) => true,
+            Request::Custom(diagnostics::DIAGNOSTICS, data) => diagnostics::clears(data),
+            _ => false,
+        };
+        let read = match req {
+            Request::ReadCoils(_, _)
+            | Request::ReadDiscreteInputs(_, _)
+            | Request::ReadHoldingRegisters(_, _)
+            | Request::ReadInputRegisters(_, _)
+            | Request::ReadWriteMultipleRegisters(_, _, _, _)
+            | Request::ReportServerId
+            | Request::Custom(diagnostics::COMM_EVENT_COUNTER, _)
+            | Request::Custom(device_id::FUNCTION_CODE, _) => true,
+            Request::Custom(diagnostics::DIAGNOSTICS, data) => !diagnostics::clears(data),
+            _ => false,
+        };
+
+        let blocked = match self.permissions {
+            // the role of a TLS client replaces the whitelists, and the Modbus/TCP Security
+            // spec answers a request the role doesn't authorize with an illegal function
+            Some(permissions) => {
+                ((write && !permissions.write) || (read && !permissions.read)).then_some(ExceptionCode::IllegalFunction)
+            }
+            None => {
+                let listed = |whitelist: &Whitelist| {
+                    whitelist.as_ref().is_none_or(|w| w.iter().any(|ip| ip.contains(self.ip.ip())))
+                };
+
+                ((write && !listed(&self.write_whitelist)) || (read && !listed(&self.read_whitelist)))
+                    .then_some(ExceptionCode::IllegalDataValue)
+            }
+        };
+
+        match blocked {
+            Some(exception) => {
+                warn!(
+                    "Blocked request {:?} from {}",
+                    req,
+                    self.ip.to_string()
+                );
+                Err(exception)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct ModbusService {
     manager: Arc<RegisterManager>,
-    read_whitelist: Option<Vec<IpNetwork>>,
-    write_whitelist: Option<Vec<IpNetwork>>,
-    ip: SocketAddr,
+    access: Access,
+    addressing: Addressing,
 }
 
 impl ModbusService {
+    /// Uses the addressing mode of the register file if it sets one, `addressing` otherwise
     pub fn new(
         manager: Arc<RegisterManager>,
         ip: SocketAddr,
-        read_whitelist: Option<Vec<IpNetwork>>,
-        write_whitelist: Option<Vec<IpNetwork>>,
+        read_whitelist: Whitelist,
+        write_whitelist: Whitelist,
+        addressing: Addressing,
     ) -> Self {
         ModbusService {
+            addressing: manager.addressing().unwrap_or(addressing),
             manager,
-            read_whitelist,
-            write_whitelist,
-            ip,
+            access: Access::new(ip, read_whitelist, write_whitelist),
         }
     }
+
+    pub fn with_permissions(mut self, permissions: Permissions) -> Self {
+        self.access = self.access.with_permissions(permissions);
+        self
+    }
+
+    /// The write to pass on to the device the register file mirrors, if any
+    pub fn write_through(&self, req: &Request) -> Option<(Arc<Upstream>, SlaveRequest<'static>)> {
+        self.manager.poller()?.write_through(self.addressing, req)
+    }
+
+    /// Checks a write to holding registers the way `call` would, without applying it
+    pub fn check_write(&self, req: &Request) -> Result<(), ExceptionCode> {
+        self.access.authorize(req)?;
+
+        let (addr, values) = match req {
+            Request::WriteSingleRegister(addr, value) => (*addr, vec![*value]),
+            Request::WriteMultipleRegisters(addr, values) => (*addr, values.to_vec()),
+            Request::MaskWriteRegister(addr, and_mask, or_mask) => {
+                let current = self.manager.read_register(self.addressing, RegisterType::HoldingRegisters, *addr, 1)?[0];
+                (*addr, vec![(current & and_mask) | (or_mask & !and_mask)])
+            }
+            Request::ReadWriteMultipleRegisters(read_addr, cnt, addr, values) => {
+                self.manager.read_register(self.addressing, RegisterType::HoldingRegisters, *read_addr, *cnt)?;
+                (*addr, values.to_vec())
+            }
+            _ => return Ok(()),
+        };
+
+        self.manager
+            .check_write(self.addressing, RegisterType::HoldingRegisters, addr, &values)
+            .map_err(|e| e.into())
+    }
 }
 
 impl From<RegisterError> for ExceptionCode {
     fn from(value: RegisterError) -> Self {
         match value {
             RegisterError::OutOfBounds => ExceptionCode::IllegalDataAddress,
+            RegisterError::ReadOnly => ExceptionCode::IllegalDataAddress,
+            RegisterError::OutOfRange => ExceptionCode::IllegalDataValue,
             RegisterError::FileWriteError => ExceptionCode::ServerDeviceFailure,
         }
     }
@@ -43,52 +165,29 @@ impl tokio_modbus::server::Service for ModbusService {
     type Future = future::Ready<Result<Response, ExceptionCode>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        if self
-            .read_whitelist
-            .as_ref()
-            .is_some_and(|w| !w.iter().any(|ip| ip.contains(self.ip.ip())))
-            && matches!(
-                req,
-                Request::WriteMultipleCoils(_, _)
-                    | Request::WriteSingleCoil(_, _)
-                    | Request::WriteMultipleRegisters(_, _)
-                    | Request::WriteSingleRegister(_, _)
-            )
-        {
-            warn!(
-                "Blocked request {:?} from {}",
-                req,
-                self.ip.to_string()
-            );
-            return future::ready(Err(ExceptionCode::IllegalDataValue));
-        }
+        let counters = self.manager.counters();
+        let event = !matches!(req, Request::Custom(diagnostics::COMM_EVENT_COUNTER, _));
 
-        if self
-            .write_whitelist
-            .as_ref()
-            .is_some_and(|w| !w.iter().any(|ip| ip.contains(self.ip.ip())))
-            && matches!(
-                req,
-                Request::ReadCoils(_, _)
-                    | Request::ReadDiscreteInputs(_, _)
-                    | Request::ReadHoldingRegisters(_, _)
-                    | Request::ReadInputRegisters(_, _)
-            )
-        {
-            warn!(
-                "Blocked request {:?} from {}",
-                req,
-                self.ip.to_string()
-            );
-            return future::ready(Err(ExceptionCode::IllegalDataValue));
+        counters.received();
+        let result = self.process(req).into_inner();
+        counters.completed(&result, event);
+
+        future::ready(result)
+    }
+}
+
+impl ModbusService {
+    fn process(&self, req: Request<'static>) -> future::Ready<Result<Response, ExceptionCode>> {
+        if let Err(exception) = self.access.authorize(&req) {
+            return future::ready(Err(exception));
         }
 
-        debug!("{}: {:?}", self.ip, req);
+        debug!("{}: {:?}", self.access.ip(), req);
 
         match req {
             Request::ReadCoils(addr, cnt) => future::ready(
                 self.manager
-                    .read_register(RegisterType::Coils, addr, cnt)
+                    .read_register(self.addressing, RegisterType::Coils, addr, cnt)
                     .map(|reg| {
                         Response::ReadCoils(reg.iter().map(|v| *v == 1).collect::<Vec<bool>>())
                     })
@@ -96,19 +195,60 @@ impl tokio_modbus::server::Service for ModbusService {
             ),
             Request::WriteSingleCoil(addr, val) => future::ready(
                 self.manager
-                    .write_register(RegisterType::Coils, addr, &[val as u16])
+                    .write_register(self.addressing, RegisterType::Coils, addr, &[val as u16])
                     .map(|_| Response::WriteSingleCoil(addr, val))
                     .map_err(|e| e.into()),
             ),
+            Request::WriteMultipleCoils(addr, coils) => future::ready(
+                self.manager
+                    .write_register(
+                        self.addressing,
+                        RegisterType::Coils,
+                        addr,
+                        &coils.iter().map(|&c| c as u16).collect::<Vec<u16>>(),
+                    )
+                    .map(|_| Response::WriteMultipleCoils(addr, coils.len() as u16))
+                    .map_err(|e| e.into()),
+            ),
+            Request::MaskWriteRegister(addr, and_mask, or_mask) => future::ready(
+                self.manager
+                    .mask_write_register(self.addressing, addr, and_mask, or_mask)
+                    .map(|_| Response::MaskWriteRegister(addr, and_mask, or_mask))
+                    .map_err(|e| e.into()),
+            ),
+            Request::ReadWriteMultipleRegisters(read_addr, cnt, write_addr, values) => future::ready(
+                self.manager
+                    .read_write_registers(self.addressing, read_addr, cnt, write_addr, &values)
+                    .map(Response::ReadWriteMultipleRegisters)
+                    .map_err(|e| e.into()),
+            ),
+            Request::Custom(device_id::FUNCTION_CODE, data) => future::ready(
+                self.manager
+                    .device_id()
+                    .ok_or(ExceptionCode::IllegalFunction)
+                    .and_then(|id| id.respond(&data))
+                    .map(|response| Response::Custom(device_id::FUNCTION_CODE, response)),
+            ),
+            Request::Custom(diagnostics::DIAGNOSTICS, data) => {
+                future::ready(self.manager.counters().diagnostics(&data))
+            }
+            Request::Custom(diagnostics::COMM_EVENT_COUNTER, data) => {
+                future::ready(self.manager.counters().comm_event_counter(&data))
+            }
+            Request::ReportServerId => future::ready(Ok(Response::ReportServerId(
+                self.manager.server_id(),
+                true,
+                concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).into(),
+            ))),
             Request::ReadInputRegisters(addr, cnt) => future::ready(
                 self.manager
-                    .read_register(RegisterType::InputRegisters, addr, cnt)
+                    .read_register(self.addressing, RegisterType::InputRegisters, addr, cnt)
                     .map(Response::ReadInputRegisters)
                     .map_err(|e| e.into()),
             ),
             Request::ReadDiscreteInputs(addr, cnt) => future::ready(
                 self.manager
-                    .read_register(RegisterType::Inputs, addr, cnt)
+                    .read_register(self.addressing, RegisterType::Inputs, addr, cnt)
                     .map(|reg| {
                         Response::ReadDiscreteInputs(
                             reg.iter().map(|v| *v == 1).collect::<Vec<bool>>(),
@@ -118,19 +258,19 @@ impl tokio_modbus::server::Service for ModbusService {
             ),
             Request::ReadHoldingRegisters(addr, cnt) => future::ready(
                 self.manager
-                    .read_register(RegisterType::HoldingRegisters, addr, cnt)
+                    .read_register(self.addressing, RegisterType::HoldingRegisters, addr, cnt)
                     .map(Response::ReadHoldingRegisters)
                     .map_err(|e| e.into()),
             ),
             Request::WriteMultipleRegisters(addr, values) => future::ready(
                 self.manager
-                    .write_register(RegisterType::HoldingRegisters, addr, &values)
+                    .write_register(self.addressing, RegisterType::HoldingRegisters, addr, &values)
                     .map(|_| Response::WriteMultipleRegisters(addr, values.len() as u16))
                     .map_err(|e| e.into()),
             ),
             Request::WriteSingleRegister(addr, value) => future::ready(
                 self.manager
-                    .write_register(RegisterType::HoldingRegisters, addr, &[value])
+                    .write_register(self.addressing, RegisterType::HoldingRegisters, addr, &[value])
                     .map(|_| Response::WriteSingleRegister(addr, 1))
                     .map_err(|e| e.into()),
             ),
@@ -147,13 +287,14 @@ mod tests {
 
     use super::ModbusService;
     use crate::{
+        addressing::Addressing,
         register_manager::{RegisterManager, RegisterType},
         util::AsWords,
     };
     use serde_json::json;
     use std::sync::Arc;
     use tokio::test;
-    use tokio_modbus::{server::Service, Request};
+    use tokio_modbus::{server::Service, ExceptionCode, Request, Response};
     type Error = Box<dyn std::error::Error>;
 
     #[test]
@@ -168,6 +309,7 @@ mod tests {
             "0.0.0.0:503".parse().unwrap(),
             None,
             None,
+            Addressing::Legacy,
         );
 
         let value: u64 = 42;
@@ -183,11 +325,292 @@ mod tests {
 
         let received = service
             .manager
-            .read_register(RegisterType::HoldingRegisters, 40007, 4)
+            .read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40007, 4)
             .unwrap();
 
         assert_eq!(value_arr, received);
 
         Ok(())
     }
+
+    #[test]
+    pub async fn access_test() -> Result<(), Error> {
+        let json = json!({
+            "40001": 1,
+            "40002": { "value": 2, "access": "ro" },
+            "40003/I": { "value": 3, "access": "once" },
+        });
+
+        let register_manager = Arc::new(RegisterManager::from_json(json).unwrap());
+        let service = ModbusService::new(
+            register_manager.clone(),
+            "0.0.0.0:503".parse().unwrap(),
+            None,
+            None,
+            Addressing::Legacy,
+        );
+
+        let result = service
+            .call(Request::WriteMultipleRegisters(40001, vec![10, 20].into()))
+            .await;
+
+        assert_eq!(result, Err(ExceptionCode::IllegalDataAddress));
+        assert_eq!(
+            register_manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40001, 2).unwrap(),
+            [1, 2]
+        );
+
+        service.call(Request::WriteSingleRegister(40001, 10)).await.unwrap();
+        service.call(Request::WriteSingleRegister(40004, 30)).await.unwrap();
+
+        let result = service
+            .call(Request::WriteMultipleRegisters(40003, vec![0, 40].into()))
+            .await;
+
+        assert_eq!(result, Err(ExceptionCode::IllegalDataAddress));
+        assert_eq!(
+            register_manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40001, 4).unwrap(),
+            [10, 2, 0, 30]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub async fn whitelist_test() -> Result<(), Error> {
+        let register_manager = Arc::new(RegisterManager::from_json(json!({ "40001": 1 })).unwrap());
+        let whitelist = Some(vec!["10.0.0.0/8".parse()?]);
+        let service = |read_whitelist, write_whitelist| {
+            ModbusService::new(
+                register_manager.clone(),
+                "192.168.0.1:503".parse().unwrap(),
+                read_whitelist,
+                write_whitelist,
+                Addressing::Legacy,
+            )
+        };
+
+        // a client missing from the read whitelist may still write
+        let write_only = service(whitelist.clone(), None);
+        assert_eq!(
+            write_only.call(Request::ReadHoldingRegisters(40001, 1)).await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        write_only.call(Request::WriteSingleRegister(40001, 2)).await.unwrap();
+
+        let read_only = service(None, whitelist);
+        assert_eq!(
+            read_only.call(Request::WriteSingleRegister(40001, 3)).await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            read_only.call(Request::ReadHoldingRegisters(40001, 1)).await,
+            Ok(Response::ReadHoldingRegisters(vec![2]))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub async fn range_test() -> Result<(), Error> {
+        let json = json!({
+            "40001/h": { "value": 20, "min": -10, "max": 50 },
+            "40002/I": { "value": 100000, "min": 0, "max": 200000 },
+            "40004/f": { "value": 0.5, "min": 0.0, "max": 1.0 },
+        });
+
+        let register_manager = Arc::new(RegisterManager::from_json(json).unwrap());
+        let service = ModbusService::new(
+            register_manager.clone(),
+            "0.0.0.0:503".parse().unwrap(),
+            None,
+            None,
+            Addressing::Legacy,
+        );
+
+        let result = service.call(Request::WriteSingleRegister(40001, -11i16 as u16)).await;
+        assert_eq!(result, Err(ExceptionCode::IllegalDataValue));
+
+        service.call(Request::WriteSingleRegister(40001, -10i16 as u16)).await.unwrap();
+
+        // only the high word is written, which combined with the low word gives 0x00040000
+        let result = service.call(Request::WriteSingleRegister(40002, 4)).await;
+        assert_eq!(result, Err(ExceptionCode::IllegalDataValue));
+
+        let result = service
+            .call(Request::WriteMultipleRegisters(40001, vec![0, 0, 1].into()))
+            .await;
+        assert!(result.is_ok());
+
+        let result = service
+            .call(Request::WriteMultipleRegisters(40004, 1.5f32.as_words().into()))
+            .await;
+        assert_eq!(result, Err(ExceptionCode::IllegalDataValue));
+
+        let result = service
+            .call(Request::WriteMultipleRegisters(40004, f32::NAN.as_words().into()))
+            .await;
+        assert_eq!(result, Err(ExceptionCode::IllegalDataValue));
+
+        assert_eq!(
+            register_manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40001, 5).unwrap(),
+            [0, 0, 1, 0x3F00, 0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub async fn write_coils_test() -> Result<(), Error> {
+        let json = json!({
+            "1": 0,
+            "2": 1,
+            "3": 0,
+            "5": 0,
+        });
+
+        let register_manager = Arc::new(RegisterManager::from_json(json).unwrap());
+        let service = ModbusService::new(
+            register_manager.clone(),
+            "0.0.0.0:503".parse().unwrap(),
+            None,
+            None,
+            Addressing::Legacy,
+        );
+
+        let response = service
+            .call(Request::WriteMultipleCoils(1, vec![true, false, true].into()))
+            .await
+            .unwrap();
+
+        assert_eq!(response, Response::WriteMultipleCoils(1, 3));
+        assert_eq!(
+            register_manager.read_register(Addressing::Legacy, RegisterType::Coils, 1, 3).unwrap(),
+            [1, 0, 1]
+        );
+
+        // coil 4 is undefined, so nothing is written
+        let result = service
+            .call(Request::WriteMultipleCoils(3, vec![false, true, true].into()))
+            .await;
+
+        assert_eq!(result, Err(ExceptionCode::IllegalDataAddress));
+        assert_eq!(register_manager.read_register(Addressing::Legacy, RegisterType::Coils, 3, 1).unwrap(), [1]);
+        assert_eq!(register_manager.read_register(Addressing::Legacy, RegisterType::Coils, 5, 1).unwrap(), [0]);
+
+        Ok(())
+    }
+
+    #[test]
+    pub async fn mask_and_read_write_test() -> Result<(), Error> {
+        let json = json!({
+            "40001": 0x12,
+            "40002": 0,
+        });
+
+        let register_manager = Arc::new(RegisterManager::from_json(json).unwrap());
+        let service = ModbusService::new(
+            register_manager.clone(),
+            "0.0.0.0:503".parse().unwrap(),
+            None,
+            None,
+            Addressing::Legacy,
+        );
+
+        let response = service.call(Request::MaskWriteRegister(40001, 0xF2, 0x25)).await.unwrap();
+        assert_eq!(response, Response::MaskWriteRegister(40001, 0xF2, 0x25));
+
+        let response = service
+            .call(Request::ReadWriteMultipleRegisters(40001, 2, 40002, vec![9].into()))
+            .await
+            .unwrap();
+        assert_eq!(response, Response::ReadWriteMultipleRegisters(vec![0x17, 9]));
+
+        let result = service.call(Request::MaskWriteRegister(40003, 0, 0)).await;
+        assert_eq!(result, Err(ExceptionCode::IllegalDataAddress));
+
+        Ok(())
+    }
+
+    #[test]
+    pub async fn diagnostics_test() -> Result<(), Error> {
+        let json = json!({
+            "40001": 1,
+            "server_id": 7,
+        });
+
+        let register_manager = Arc::new(RegisterManager::from_json(json).unwrap());
+        let service = ModbusService::new(
+            register_manager.clone(),
+            "0.0.0.0:503".parse().unwrap(),
+            None,
+            None,
+            Addressing::Legacy,
+        );
+
+        service.call(Request::ReadHoldingRegisters(40001, 1)).await.unwrap();
+        service.call(Request::ReadHoldingRegisters(40002, 1)).await.unwrap_err();
+
+        // bus exception error count
+        let response = service.call(Request::Custom(0x08, vec![0x00, 0x0D, 0x00, 0x00].into())).await;
+        assert_eq!(response, Ok(Response::Custom(0x08, vec![0x00, 0x0D, 0x00, 0x01].into())));
+
+        // bus message count, including the request itself
+        let response = service.call(Request::Custom(0x08, vec![0x00, 0x0B, 0x00, 0x00].into())).await;
+        assert_eq!(response, Ok(Response::Custom(0x08, vec![0x00, 0x0B, 0x00, 0x04].into())));
+
+        let response = service.call(Request::Custom(0x0B, vec![].into())).await;
+        assert_eq!(response, Ok(Response::Custom(0x0B, vec![0x00, 0x00, 0x00, 0x03].into())));
+
+        let response = service.call(Request::ReportServerId).await.unwrap();
+        assert!(matches!(response, Response::ReportServerId(7, true, _)));
+
+        assert!(RegisterManager::from_json(json!({ "server_id": 256 })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub async fn addressing_test() -> Result<(), Error> {
+        let json = json!({
+            "1": 1,
+            "40001": 10,
+            "40002": 20,
+        });
+
+        let register_manager = Arc::new(RegisterManager::from_json(json).unwrap());
+        let service = |addressing| {
+            ModbusService::new(register_manager.clone(), "0.0.0.0:503".parse().unwrap(), None, None, addressing)
+        };
+
+        let response = service(Addressing::ZeroBased).call(Request::ReadHoldingRegisters(0, 2)).await;
+        assert_eq!(response, Ok(Response::ReadHoldingRegisters(vec![10, 20])));
+
+        let response = service(Addressing::OneBased).call(Request::ReadHoldingRegisters(2, 1)).await;
+        assert_eq!(response, Ok(Response::ReadHoldingRegisters(vec![20])));
+
+        let response = service(Addressing::ZeroBased).call(Request::ReadCoils(0, 1)).await;
+        assert_eq!(response, Ok(Response::ReadCoils(vec![true])));
+
+        service(Addressing::ZeroBased).call(Request::WriteSingleRegister(1, 30)).await.unwrap();
+        assert_eq!(
+            register_manager.read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40002, 1).unwrap(),
+            [30]
+        );
+
+        let response = service(Addressing::OneBased).call(Request::ReadHoldingRegisters(0, 1)).await;
+        assert_eq!(response, Err(ExceptionCode::IllegalDataAddress));
+
+        let response = service(Addressing::Legacy).call(Request::ReadHoldingRegisters(0, 1)).await;
+        assert_eq!(response, Err(ExceptionCode::IllegalDataAddress));
+
+        // the register file overrides the mode of the listener
+        let register_manager = Arc::new(RegisterManager::from_json(json!({ "40001": 10, "addressing": "zero-based" })).unwrap());
+        let service = ModbusService::new(register_manager, "0.0.0.0:503".parse().unwrap(), None, None, Addressing::Legacy);
+
+        let response = service.call(Request::ReadHoldingRegisters(0, 1)).await;
+        assert_eq!(response, Ok(Response::ReadHoldingRegisters(vec![10])));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,312 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use x509_parser::{
+    der_parser::{der::parse_der_utf8string, oid::Oid},
+    prelude::{FromDer, X509Certificate},
+};
+
+use crate::{
+    framing,
+    udp::{self, HEADER_LEN, MAX_DATAGRAM_LEN},
+    units::{UnitMap, UnitService},
+    validation::Permissions,
+};
+
+/// OID of the role extension of client certificates, from the Modbus/TCP Security spec
+const ROLE_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 50316, 802, 1];
+
+/// Longest a client may take to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TlsConfig {
+    pub socket_addr: SocketAddr,
+    /// PEM certificate chain of the server
+    pub cert: String,
+    /// PEM private key of the server
+    pub key: String,
+    /// PEM certificates of the CAs allowed to sign client certificates
+    pub ca: String,
+    /// Permissions granted by each role, roles not listed get none
+    pub roles: HashMap<String, Permissions>,
+}
+
+impl TlsConfig {
+    /// TLS 1.2 or later, with a client certificate signed by one of the CAs required
+    pub fn acceptor(&self) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)?.collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(&self.key)?;
+
+        let mut roots = RootCertStore::empty();
+        for ca in CertificateDer::pem_file_iter(&self.ca)? {
+            roots.add(ca?)?;
+        }
+
+        let config = ServerConfig::builder()
+            .with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+            .with_single_cert(certs, key)?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Role of a client certificate, the UTF8String of its role extension
+pub fn role(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let extension = cert.get_extension_unique(&Oid::from(ROLE_OID).ok()?).ok()??;
+    let (_, value) = parse_der_utf8string(extension.value).ok()?;
+
+    value.as_str().ok().map(String::from)
+}
+
+/// Serves Modbus/TCP Security, with the permissions of each client granted by its role
+pub async fn serve<F>(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    roles: &HashMap<String, Permissions>,
+    unit_map: Arc<UnitMap>,
+    new_service: F,
+) -> std::io::Result<()>
+where
+    F: Fn(SocketAddr) -> UnitService,
+{
+    let roles = Arc::new(roles.clone());
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+
+        let service = new_service(addr);
+        let acceptor = acceptor.clone();
+        let roles = roles.clone();
+        let unit_map = unit_map.clone();
+
+        // the handshake runs with the connection, so a client stalling it holds up no other
+        tokio::spawn(async move {
+            // a failed handshake only drops the connection, never the listener
+            let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!("TLS handshake with {} failed: {}", addr, e);
+                    return;
+                }
+                Err(_) => {
+                    warn!("TLS handshake with {} timed out", addr);
+                    return;
+                }
+            };
+
+            let role = stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()).and_then(role);
+            let permissions = role.as_ref().and_then(|role| roles.get(role)).copied().unwrap_or_default();
+            info!("TLS client {} with role {:?}: {:?}", addr, role, permissions);
+
+            if let Err(e) = process(stream, &unit_map, service.with_permissions(permissions)).await {
+                error!("TLS connection from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Answers the MBAP framed requests of one connection until it is closed
+async fn process<S>(mut stream: S, unit_map: &UnitMap, service: UnitService) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut adu = [0u8; MAX_DATAGRAM_LEN];
+
+    loop {
+        match stream.read_exact(&mut adu[..HEADER_LEN]).await {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            read => read?,
+        };
+
+        // the length counts the unit ID, and one that doesn't fit a frame leaves no way to find the next
+        let len = HEADER_LEN - 1 + u16::from_be_bytes([adu[4], adu[5]]) as usize;
+        if !(HEADER_LEN + 1..=MAX_DATAGRAM_LEN).contains(&len) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Invalid MBAP length of {} bytes", len)));
+        }
+
+        stream.read_exact(&mut adu[HEADER_LEN..len]).await?;
+        let (transaction, frame) = udp::decode(&adu[..len]);
+
+        if let Some((slave, response)) = framing::answer(frame, unit_map, &service).await {
+            stream.write_all(&udp::encode(transaction, slave, &response)).await?;
+            stream.flush().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        addressing::Addressing,
+        register_manager::RegisterManager,
+        units::{UnitMap, UnknownUnit},
+    };
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, CustomExtension, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use serde_json::json;
+    use tokio::{net::TcpStream, test};
+    use tokio_rustls::{
+        rustls::{pki_types::ServerName, ClientConfig},
+        TlsConnector,
+    };
+    type Error = Box<dyn std::error::Error>;
+
+    fn client_cert(role: Option<&str>, ca: &Certificate, ca_key: &KeyPair) -> Result<(Certificate, KeyPair), Error> {
+        let mut params = CertificateParams::new(vec![])?;
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        if let Some(role) = role {
+            let mut value = vec![0x0C, role.len() as u8];
+            value.extend_from_slice(role.as_bytes());
+            params.custom_extensions.push(CustomExtension::from_oid_content(ROLE_OID, value));
+        }
+
+        let key = KeyPair::generate()?;
+        Ok((params.signed_by(&key, ca, ca_key)?, key))
+    }
+
+    async fn request(
+        connector: &TlsConnector,
+        addr: SocketAddr,
+        adu: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(ServerName::try_from("localhost")?, stream).await?;
+        stream.write_all(adu).await?;
+
+        let mut response = vec![0u8; 64];
+        let len = stream.read(&mut response).await?;
+        response.truncate(len);
+        Ok(response)
+    }
+
+    #[test]
+    pub async fn tls_test() -> Result<(), Error> {
+        let ca_key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![])?;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key)?;
+
+        let server_key = KeyPair::generate()?;
+        let server = CertificateParams::new(vec!["localhost".into()])?.signed_by(&server_key, &ca, &ca_key)?;
+
+        let dir = std::env::temp_dir().join(format!("rust-modbus-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("server.pem"), server.pem())?;
+        std::fs::write(dir.join("server.key"), server_key.serialize_pem())?;
+        std::fs::write(dir.join("ca.pem"), ca.pem())?;
+
+        let config = TlsConfig {
+            socket_addr: "127.0.0.1:0".parse()?,
+            cert: dir.join("server.pem").to_string_lossy().into(),
+            key: dir.join("server.key").to_string_lossy().into(),
+            ca: dir.join("ca.pem").to_string_lossy().into(),
+            roles: HashMap::from([
+                ("operator".to_string(), Permissions { read: true, write: true }),
+                ("viewer".to_string(), Permissions { read: true, write: false }),
+            ]),
+        };
+        let acceptor = config.acceptor()?;
+        std::fs::remove_dir_all(&dir)?;
+
+        let manager = Arc::new(RegisterManager::from_json(json!({ "40001": 7 })).unwrap());
+        let unit_map = Arc::new(UnitMap::new(HashMap::new(), Some(manager), UnknownUnit::Default));
+
+        let listener = TcpListener::bind(config.socket_addr).await?;
+        let addr = listener.local_addr()?;
+
+        // the whitelists would block everything from localhost, the roles decide instead
+        let whitelist = Some(vec!["10.0.0.0/8".parse()?]);
+        let server = tokio::spawn(async move {
+            serve(listener, acceptor, &config.roles, unit_map.clone(), |addr| {
+                UnitService::new(&unit_map, addr, whitelist.clone(), whitelist.clone(), Addressing::ZeroBased)
+            })
+            .await
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone())?;
+
+        let connector = |role: Option<&str>| -> Result<TlsConnector, Error> {
+            let (cert, key) = client_cert(role, &ca, &ca_key)?;
+            assert_eq!(super::role(cert.der()).as_deref(), role);
+
+            let config = ClientConfig::builder()
+                .with_root_certificates(roots.clone())
+                .with_client_auth_cert(vec![cert.der().clone()], PrivateKeyDer::Pkcs8(key.serialize_der().into()))?;
+            Ok(TlsConnector::from(Arc::new(config)))
+        };
+
+        let write = [0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x01, 0x06, 0x00, 0x00, 0x00, 0x2A];
+        let read = [0x00, 0x02, 0x00, 0x00, 0x00, 0x06, 0x01, 0x03, 0x00, 0x00, 0x00, 0x01];
+        let clear_counters = [0x00, 0x03, 0x00, 0x00, 0x00, 0x06, 0x01, 0x08, 0x00, 0x0A, 0x00, 0x00];
+        let message_count = [0x00, 0x04, 0x00, 0x00, 0x00, 0x06, 0x01, 0x08, 0x00, 0x0E, 0x00, 0x00];
+        let event_counter = [0x00, 0x05, 0x00, 0x00, 0x00, 0x02, 0x01, 0x0B];
+        let server_id = [0x00, 0x06, 0x00, 0x00, 0x00, 0x02, 0x01, 0x11];
+
+        // a client that never starts its handshake holds up no other
+        let _stalled = TcpStream::connect(addr).await?;
+
+        let operator = connector(Some("operator"))?;
+        let response = tokio::time::timeout(Duration::from_secs(2), request(&operator, addr, &write)).await??;
+        assert_eq!(response[7..], [0x06, 0x00, 0x00, 0x00, 0x01]);
+
+        let viewer = connector(Some("viewer"))?;
+        assert_eq!(request(&viewer, addr, &write).await?[7..], [0x86, 0x01]);
+        assert_eq!(request(&viewer, addr, &read).await?[7..], [0x03, 0x02, 0x00, 0x2A]);
+
+        // clearing the counters is a write, the other diagnostics are reads
+        assert_eq!(request(&viewer, addr, &clear_counters).await?[7..], [0x88, 0x01]);
+        assert_eq!(request(&viewer, addr, &message_count).await?[7..9], [0x08, 0x00]);
+        assert_eq!(request(&viewer, addr, &event_counter).await?[7], 0x0B);
+        assert_eq!(request(&operator, addr, &clear_counters).await?[7..], [0x08, 0x00, 0x0A, 0x00, 0x00]);
+
+        // requests arriving together on one connection are answered in turn
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = viewer.connect(ServerName::try_from("localhost")?, stream).await?;
+        stream.write_all(&[read, read].concat()).await?;
+        let mut responses = [0u8; 22];
+        stream.read_exact(&mut responses).await?;
+        assert_eq!(responses[7..11], [0x03, 0x02, 0x00, 0x2A]);
+        assert_eq!(responses[18..], [0x03, 0x02, 0x00, 0x2A]);
+
+        // no role, or one that isn't mapped, grants nothing
+        for role in [None, Some("guest")] {
+            let client = connector(role)?;
+            assert_eq!(request(&client, addr, &read).await?[7..], [0x83, 0x01]);
+            assert_eq!(request(&client, addr, &message_count).await?[7..], [0x88, 0x01]);
+            assert_eq!(request(&client, addr, &event_counter).await?[7..], [0x8B, 0x01]);
+            assert_eq!(request(&client, addr, &server_id).await?[7..], [0x91, 0x01]);
+        }
+
+        // without a client certificate the handshake fails
+        let anonymous = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let anonymous = TlsConnector::from(Arc::new(anonymous));
+        assert!(request(&anonymous, addr, &read).await.is_err());
+
+        server.abort();
+
+        Ok(())
+    }
+}
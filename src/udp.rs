@@ -0,0 +1,128 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use log::{debug, info, warn};
+use tokio::net::UdpSocket;
+use tokio_modbus::bytes::Bytes;
+
+use crate::{
+    framing::{self, Frame, FrameError},
+    units::{UnitMap, UnitService},
+};
+
+/// Length of the MBAP header, unit ID included
+pub const HEADER_LEN: usize = 7;
+
+/// Longest MBAP frame: the header and a 253 byte PDU
+pub const MAX_DATAGRAM_LEN: usize = HEADER_LEN + 253;
+
+/// Splits a datagram, or any other MBAP frame, into its transaction ID and frame
+pub fn decode(datagram: &[u8]) -> (u16, Result<Frame, FrameError>) {
+    let transaction = match datagram {
+        [high, low, ..] => u16::from_be_bytes([*high, *low]),
+        _ => 0,
+    };
+
+    if datagram.len() > MAX_DATAGRAM_LEN {
+        return (transaction, Err(FrameError::Oversized(datagram.len())));
+    }
+
+    let frame = match datagram {
+        [_, _, 0, 0, len_high, len_low, unit, pdu @ ..]
+            if !pdu.is_empty() && u16::from_be_bytes([*len_high, *len_low]) as usize == pdu.len() + 1 =>
+        {
+            Ok((*unit, Bytes::copy_from_slice(pdu)))
+        }
+        _ => Err(FrameError::Malformed),
+    };
+
+    (transaction, frame)
+}
+
+pub fn encode(transaction: u16, slave: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(HEADER_LEN + pdu.len());
+    datagram.extend_from_slice(&transaction.to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    datagram.push(slave);
+    datagram.extend_from_slice(pdu);
+    datagram
+}
+
+/// Answers MBAP framed datagrams, one request per datagram
+pub async fn serve<F>(socket: UdpSocket, unit_map: Arc<UnitMap>, new_service: F) -> std::io::Result<()>
+where
+    F: Fn(SocketAddr) -> UnitService,
+{
+    info!("UDP listening on {}", socket.local_addr()?);
+
+    // one byte more than the largest frame, so oversized datagrams can be told apart
+    let mut buf = [0u8; MAX_DATAGRAM_LEN + 1];
+
+    loop {
+        // errors about a single peer, such as an ICMP port unreachable, leave the socket usable
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("UDP receive failed: {}", e);
+                continue;
+            }
+        };
+        let (transaction, frame) = decode(&buf[..len]);
+
+        // the whitelists are checked against the sender of every datagram
+        let service = new_service(peer);
+
+        if let Some((slave, response)) = framing::answer(frame, &unit_map, &service).await {
+            debug!("Answering transaction {} from {}", transaction, peer);
+            if let Err(e) = socket.send_to(&encode(transaction, slave, &response), peer).await {
+                warn!("Answering {} failed: {}", peer, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{addressing::Addressing, register_manager::RegisterManager, units::UnknownUnit};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use tokio::test;
+    type Error = Box<dyn std::error::Error>;
+
+    #[test]
+    pub async fn udp_test() -> Result<(), Error> {
+        let manager = Arc::new(RegisterManager::from_json(json!({ "40001": 7 })).unwrap());
+        let unit_map = Arc::new(UnitMap::new(HashMap::new(), Some(manager.clone()), UnknownUnit::Default));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = socket.local_addr()?;
+        let whitelist = Some(vec!["10.0.0.0/8".parse()?]);
+        let server = tokio::spawn(serve(socket, unit_map.clone(), move |peer| {
+            UnitService::new(&unit_map, peer, None, whitelist.clone(), Addressing::ZeroBased)
+        }));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        client.connect(addr).await?;
+        let mut buf = [0u8; 300];
+
+        // oversized, malformed and a bad protocol ID are all dropped
+        client.send(&[0u8; MAX_DATAGRAM_LEN + 1]).await?;
+        client.send(&[0x00, 0x01, 0x00]).await?;
+        client.send(&[0x00, 0x01, 0x00, 0x01, 0x00, 0x06, 0x01, 0x03, 0x00, 0x00, 0x00, 0x01]).await?;
+
+        // writes are only allowed from the whitelist, so this one gets an exception
+        client.send(&encode(0x1234, 1, &[0x06, 0x00, 0x00, 0x00, 0x01])).await?;
+        let len = client.recv(&mut buf).await?;
+        assert_eq!(&buf[..len], encode(0x1234, 1, &[0x86, 0x03]));
+
+        // bus communication error count
+        client.send(&encode(0x1235, 1, &[0x08, 0x00, 0x0C, 0x00, 0x00])).await?;
+        let len = client.recv(&mut buf).await?;
+        assert_eq!(&buf[..len], encode(0x1235, 1, &[0x08, 0x00, 0x0C, 0x00, 0x03]));
+
+        server.abort();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use std::{collections::HashMap, future::{self, Future}, net::SocketAddr, pin::Pin, sync::Arc};
+
+use log::debug;
+use tokio_modbus::{ExceptionCode, Response, SlaveRequest};
+
+use crate::{
+    addressing::Addressing,
+    gateway::Gateway,
+    register_manager::RegisterManager,
+    service::{Access, ModbusService},
+    validation::{Permissions, Whitelist},
+};
+
+/// How to answer requests for a unit ID without its own register map
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum UnknownUnit {
+    /// Answer from the default map (data.json)
+    #[default]
+    Default,
+    /// Send no response at all
+    Drop,
+    /// Respond with a gateway target device exception
+    Exception,
+}
+
+/// The register maps of every unit served by the process
+pub struct UnitMap {
+    units: HashMap<u8, Arc<RegisterManager>>,
+    default: Option<Arc<RegisterManager>>,
+    unknown: UnknownUnit,
+    gateway: Option<Arc<Gateway>>,
+}
+
+impl UnitMap {
+    pub fn new(
+        units: HashMap<u8, Arc<RegisterManager>>,
+        default: Option<Arc<RegisterManager>>,
+        unknown: UnknownUnit,
+    ) -> Self {
+        UnitMap {
+            units,
+            default,
+            unknown,
+            gateway: None,
+        }
+    }
+
+    /// Forwards requests for units without a register map along the routes of `gateway`
+    pub fn with_gateway(mut self, gateway: Gateway) -> Self {
+        self.gateway = Some(Arc::new(gateway));
+        self
+    }
+
+    /// The same register maps on a shared serial line. The default map answers
+    /// to `slave_id` only, and requests for any other unit are dropped.
+    pub fn on_bus(&self, slave_id: u8) -> UnitMap {
+        let mut units = self.units.clone();
+
+        if let Some(default) = &self.default {
+            units.entry(slave_id).or_insert_with(|| default.clone());
+        }
+
+        UnitMap {
+            units,
+            default: None,
+            unknown: UnknownUnit::Drop,
+            gateway: self.gateway.clone(),
+        }
+    }
+
+    /// Whether requests for `unit` are answered or forwarded rather than dropped
+    pub fn serves(&self, unit: u8) -> bool {
+        self.units.contains_key(&unit)
+            || self.gateway.as_ref().is_some_and(|gateway| gateway.routes_unit(unit))
+            || match self.unknown {
+                UnknownUnit::Default => self.default.is_some(),
+                UnknownUnit::Exception => true,
+                UnknownUnit::Drop => false,
+            }
+    }
+
+    /// Every register map, including the default one
+    pub fn managers(&self) -> impl Iterator<Item = &Arc<RegisterManager>> {
+        self.units.values().chain(self.default.iter())
+    }
+}
+
+/// Dispatches requests to the `ModbusService` of their unit ID
+pub struct UnitService {
+    units: HashMap<u8, ModbusService>,
+    default: Option<ModbusService>,
+    unknown: UnknownUnit,
+    gateway: Option<Arc<Gateway>>,
+    /// Checked before forwarding, as the register maps check their own requests
+    access: Access,
+    addressing: Addressing,
+}
+
+impl UnitService {
+    pub fn new(
+        map: &UnitMap,
+        ip: SocketAddr,
+        read_whitelist: Whitelist,
+        write_whitelist: Whitelist,
+        addressing: Addressing,
+    ) -> Self {
+        let service = |manager: &Arc<RegisterManager>| {
+            ModbusService::new(manager.clone(), ip, read_whitelist.clone(), write_whitelist.clone(), addressing)
+        };
+
+        UnitService {
+            units: map.units.iter().map(|(id, m)| (*id, service(m))).collect(),
+            default: map.default.as_ref().map(service),
+            unknown: map.unknown,
+            gateway: map.gateway.clone(),
+            access: Access::new(ip, read_whitelist.clone(), write_whitelist.clone()),
+            addressing,
+        }
+    }
+}
+
+impl UnitService {
+    /// Grants every unit the permissions of a TLS client's role, in place of the whitelists
+    pub fn with_permissions(self, permissions: Permissions) -> Self {
+        UnitService {
+            units: self.units.into_iter().map(|(id, s)| (id, s.with_permissions(permissions))).collect(),
+            default: self.default.map(|s| s.with_permissions(permissions)),
+            access: self.access.with_permissions(permissions),
+            ..self
+        }
+    }
+}
+
+impl tokio_modbus::server::Service for UnitService {
+    type Exception = ExceptionCode;
+    type Response = Option<Response>;
+    type Request = SlaveRequest<'static>;
+    type Future = Pin<Box<dyn Future<Output = Result<Option<Response>, ExceptionCode>> + Send>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        // requests for units without a register map of their own go upstream if a route matches
+        if !self.units.contains_key(&req.slave) {
+            if let Some(upstream) = self.gateway.as_ref().and_then(|g| g.route(self.addressing, &req)) {
+                if let Err(exception) = self.access.authorize(&req.request) {
+                    return Box::pin(future::ready(Err(exception)));
+                }
+
+                return Box::pin(upstream.relay(self.addressing, req));
+            }
+        }
+
+        let service = match (self.units.get(&req.slave), self.unknown) {
+            (Some(service), _) => service,
+            (None, UnknownUnit::Default) if self.default.is_some() => self.default.as_ref().unwrap(),
+            (None, UnknownUnit::Exception) => {
+                debug!("No register map for unit {}", req.slave);
+                return Box::pin(future::ready(Err(ExceptionCode::GatewayTargetDevice)));
+            }
+            (None, _) => {
+                debug!("Dropped request for unit {}", req.slave);
+                return Box::pin(future::ready(Ok(None)));
+            }
+        };
+
+        // a write to registers mirrored from a polled device is checked locally, passed on, and
+        // only applied once the device has taken it so the mirror never holds a value it doesn't have
+        if let Some((upstream, write)) = service.write_through(&req.request) {
+            if let Err(exception) = service.check_write(&req.request) {
+                return Box::pin(future::ready(Err(exception)));
+            }
+
+            let service = service.clone();
+            return Box::pin(async move {
+                upstream.forward(write).await?;
+                tokio_modbus::server::Service::call(&service, req.request).into_inner().map(Some)
+            });
+        }
+
+        Box::pin(future::ready(tokio_modbus::server::Service::call(service, req.request).into_inner().map(Some)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register_manager::RegisterType;
+    use serde_json::json;
+    use tokio::test;
+    use tokio_modbus::{server::Service, Request};
+    type Error = Box<dyn std::error::Error>;
+
+    fn read(slave: u8) -> SlaveRequest<'static> {
+        SlaveRequest {
+            slave,
+            request: Request::ReadHoldingRegisters(40001, 1),
+        }
+    }
+
+    #[test]
+    pub async fn unit_test() -> Result<(), Error> {
+        let manager = |value: u16| Arc::new(RegisterManager::from_json(json!({ "40001": value })).unwrap());
+        let units = HashMap::from([(1, manager(1)), (2, manager(2))]);
+        let default = manager(0);
+        let addr = "0.0.0.0:503".parse().unwrap();
+
+        let service = UnitService::new(
+            &UnitMap::new(units.clone(), Some(default.clone()), UnknownUnit::Default),
+            addr,
+            None,
+            None,
+            Addressing::Legacy,
+        );
+
+        assert_eq!(service.call(read(1)).await, Ok(Some(Response::ReadHoldingRegisters(vec![1]))));
+        assert_eq!(service.call(read(2)).await, Ok(Some(Response::ReadHoldingRegisters(vec![2]))));
+        assert_eq!(service.call(read(3)).await, Ok(Some(Response::ReadHoldingRegisters(vec![0]))));
+
+        service
+            .call(SlaveRequest { slave: 2, request: Request::WriteSingleRegister(40001, 20) })
+            .await
+            .unwrap();
+
+        assert_eq!(units[&1].read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40001, 1).unwrap(), [1]);
+        assert_eq!(units[&2].read_register(Addressing::Legacy, RegisterType::HoldingRegisters, 40001, 1).unwrap(), [20]);
+
+        let service = UnitService::new(&UnitMap::new(units.clone(), None, UnknownUnit::Drop), addr, None, None, Addressing::Legacy);
+        assert_eq!(service.call(read(3)).await, Ok(None));
+
+        let service = UnitService::new(&UnitMap::new(units, None, UnknownUnit::Exception), addr, None, None, Addressing::Legacy);
+        assert_eq!(service.call(read(3)).await, Err(ExceptionCode::GatewayTargetDevice));
+
+        Ok(())
+    }
+}
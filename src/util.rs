@@ -1,101 +1,112 @@
+use crate::pack::ByteOrder;
+
 pub trait AsWords<T> {
     #[allow(unused)]
     fn as_words(&self) -> Vec<u16>;
+
+    /// Same as `as_words`, but laid out in `order` rather than big-endian
+    #[allow(unused)]
+    fn as_words_ordered(&self, order: ByteOrder) -> Vec<u16> {
+        order.apply(&self.as_words())
+    }
 }
 
 pub trait FromVec<T> {
-    #[allow(unused)]
+    #[allow(unused, clippy::wrong_self_convention)]
     fn from_vec(&self) -> T;
-}
 
-impl FromVec<u64> for Vec<u16> {
-    fn from_vec(&self) -> u64 {
-        if self.len() != 4 {
-            panic!("Invalid vec");
-        }
+    /// Same as `from_vec`, but reads words laid out in `order` rather than big-endian
+    #[allow(unused, clippy::wrong_self_convention)]
+    fn from_vec_ordered(&self, order: ByteOrder) -> T;
+}
 
-        let msb = (self[0] as u64) << 48;
-        let second = (self[1] as u64) << 32;
-        let third = (self[2] as u64) << 16;
-        let lsb = self[3] as u64;
-        
+macro_rules! impl_words {
+    ($($t:ty),*) => {$(
+        impl FromVec<$t> for Vec<u16> {
+            fn from_vec(&self) -> $t {
+                const LEN: usize = std::mem::size_of::<$t>();
 
-        msb | second | third | lsb
-    }
-}
+                if self.len() * 2 != LEN {
+                    panic!("Invalid vec");
+                }
 
-impl FromVec<i64> for Vec<u16> {
-    fn from_vec(&self) -> i64 {
-        if self.len() != 4 {
-            panic!("Invalid vec");
-        }
+                let mut bytes = [0u8; LEN];
+                for (chunk, word) in bytes.chunks_mut(2).zip(self) {
+                    chunk.copy_from_slice(&word.to_be_bytes());
+                }
 
-        let msb = (self[0] as i64) << 48;
-        let second = (self[1] as i64) << 32;
-        let third = (self[2] as i64) << 16;
-        let lsb = self[3] as i64;
-        
+                <$t>::from_be_bytes(bytes)
+            }
 
-        msb | second | third | lsb
-    }
-}
+            fn from_vec_ordered(&self, order: ByteOrder) -> $t {
+                order.apply(self).from_vec()
+            }
+        }
 
-impl AsWords<u64> for u64 {
-    fn as_words(&self) -> Vec<u16> {
-        vec![
-            ((self >> 48) & 0xFFFF) as u16,
-            ((self >> 32) & 0xFFFF) as u16,
-            ((self >> 16) & 0xFFFF) as u16,
-            (self & 0xFFFF) as u16,
-        ]
-    }
+        impl AsWords<$t> for $t {
+            fn as_words(&self) -> Vec<u16> {
+                self.to_be_bytes()
+                    .chunks(2)
+                    .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+                    .collect()
+            }
+        }
+    )*};
 }
 
-impl AsWords<i64> for i64 {
-    fn as_words(&self) -> Vec<u16> {
-        vec![
-            ((self >> 48) & 0xFFFF) as u16,
-            ((self >> 32) & 0xFFFF) as u16,
-            ((self >> 16) & 0xFFFF) as u16,
-            (self & 0xFFFF) as u16,
-        ]
-    }
-}
+impl_words!(u16, i16, u32, i32, u64, i64, f32, f64);
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
-    // type Error = Box<dyn std::error::Error>;
+    use super::*;
+    type Error = Box<dyn std::error::Error>;
+
+    #[test]
+    pub fn test_u64() -> Result<(), Error> {
+
+        let num = 12u64;
+
+        let as_words = num.as_words();
 
-    // #[test]
-    // pub fn test_u64() -> Result<(), Error> {
+        assert_eq!(as_words, vec![0x0000, 0x0000, 0x0000, 0x000C]);
 
-    //     let num = 12u64;
+        let from_vec: u64 = as_words.from_vec();
 
-    //     let as_words = num.as_words();
+        assert_eq!(from_vec, 12u64);
 
-    //     assert_eq!(as_words, vec![0x0000, 0x0000, 0x0000, 0x000C]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_i64() -> Result<(), Error> {
 
-    //     let from_vec: u64 = as_words.from_vec();
+        let num = -14i64;
 
-    //     assert_eq!(from_vec, 12u64);
+        let as_words = num.as_words();
 
-    //     Ok(())
-    // }
+        assert_eq!(as_words, vec![0xFFFF, 0xFFFF, 0xFFFF, 0xFFF2]);
 
-    // #[test]
-    // pub fn test_i64() -> Result<(), Error> {
+        let from_vec: i64 = as_words.from_vec();
 
-    //     let num = -14i64;
+        assert_eq!(from_vec, -14i64);
+
+        Ok(())
+    }
 
-    //     let as_words = num.as_words();
+    #[test]
+    pub fn test_ordered() -> Result<(), Error> {
 
-    //     assert_eq!(as_words, vec![0xFFFF, 0xFFFF, 0xFFFF, 0xFFF2]);
+        let num = 0xAABBCCDDu32;
 
-    //     let from_vec: i64 = as_words.from_vec();
+        assert_eq!(num.as_words_ordered(ByteOrder::BigEndian), vec![0xAABB, 0xCCDD]);
+        assert_eq!(num.as_words_ordered(ByteOrder::LittleEndian), vec![0xDDCC, 0xBBAA]);
+        assert_eq!(num.as_words_ordered(ByteOrder::BigEndianWordSwap), vec![0xCCDD, 0xAABB]);
+        assert_eq!(num.as_words_ordered(ByteOrder::LittleEndianWordSwap), vec![0xBBAA, 0xDDCC]);
 
-    //     assert_eq!(from_vec, -14i64);
+        let from_vec: u32 = vec![0xCCDD, 0xAABB].from_vec_ordered(ByteOrder::BigEndianWordSwap);
 
-    //     Ok(())
-    // }
-}
\ No newline at end of file
+        assert_eq!(from_vec, num);
+
+        Ok(())
+    }
+}
@@ -1,11 +1,18 @@
-use std::time::Duration;
+use std::{net::SocketAddr, time::Duration};
 
 use ipnetwork::IpNetwork;
 
+use crate::{
+    gateway::{Route, DEFAULT_TIMEOUT},
+    register_manager::RegisterType,
+};
+
+
+pub type Whitelist = Option<Vec<IpNetwork>>;
 
 pub fn parse_whitelist(
     target: Vec<String>,
-) -> Result<(Option<Vec<IpNetwork>>, Option<Vec<IpNetwork>>), String> {
+) -> Result<(Whitelist, Whitelist), String> {
     let mut read_whitelist: Vec<IpNetwork> = Vec::new();
     let mut write_whitelist: Vec<IpNetwork> = Vec::new();
 
@@ -31,12 +38,13 @@ pub fn parse_whitelist(
     }
 
     Ok((
-        Some(read_whitelist).filter(|w| w.len() > 0),
-        Some(write_whitelist).filter(|w| w.len() > 0),
+        Some(read_whitelist).filter(|w| !w.is_empty()),
+        Some(write_whitelist).filter(|w| !w.is_empty()),
     ))
 }
 
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Op {
     Read,
     Write,
@@ -54,6 +62,94 @@ impl Op {
     }
 }
 
+/// What a TLS client may do, granted by the role in its certificate
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+}
+
+impl From<Op> for Permissions {
+    fn from(op: Op) -> Self {
+        Permissions {
+            read: matches!(op, Op::Read | Op::ReadWrite),
+            write: matches!(op, Op::Write | Op::ReadWrite),
+        }
+    }
+}
+
+
+/// Parses a certificate role to permissions mapping such as `operator=rw`
+pub fn parse_role(val: &str) -> Result<(String, Permissions), String> {
+    val.split_once('=')
+        .filter(|(role, _)| !role.is_empty())
+        .ok_or_else(|| String::from("The role must be given as <role>=<r|w|rw>"))
+        .and_then(|(role, op)| Op::parse(op).map(|op| (role.to_string(), op.into())))
+}
+
+
+/// Parses a gateway route such as `5=10.0.0.2:502`, `300001-300100=10.0.0.2:502` or
+/// `5:400001-400100=10.0.0.2:502@500ms`
+pub fn parse_route(val: &str) -> Result<Route, String> {
+    let (matcher, target) = val
+        .split_once('=')
+        .ok_or_else(|| String::from("The route must be given as [<unit>][:<first>-<last>]=<ip:port>[@<timeout>]"))?;
+
+    let (upstream, timeout) = match target.split_once('@') {
+        Some((upstream, timeout)) => (upstream, validate_time(timeout)?),
+        None => (target, DEFAULT_TIMEOUT),
+    };
+
+    let (unit, range) = match matcher.split_once(':') {
+        Some((unit, range)) => (Some(unit), Some(range)),
+        None if matcher.contains('-') => (None, Some(matcher)),
+        None => (Some(matcher), None),
+    };
+
+    Ok(Route {
+        unit: unit
+            .map(|unit| unit.parse::<u8>().map_err(|e| format!("Error parsing unit ID: {}", e)))
+            .transpose()?,
+        range: range.map(parse_range).transpose()?,
+        upstream: upstream
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("Error parsing upstream address: {}", e))?,
+        timeout,
+    })
+}
+
+/// Parses a range of register numbers within one table, such as `400001-400100`
+fn parse_range(val: &str) -> Result<(u32, u32), String> {
+    let table = |number: &str| {
+        number
+            .parse::<u32>()
+            .ok()
+            .and_then(|number| RegisterType::locate(number).map(|(table, _)| (number, table)))
+    };
+
+    match val.split_once('-').map(|(first, last)| (table(first), table(last))) {
+        Some((Some((first, first_table)), Some((last, last_table)))) if first <= last && first_table == last_table => {
+            Ok((first, last))
+        }
+        _ => Err(String::from(
+            "The range must be given as the first and last register number of one table, e.g. 400001-400100",
+        )),
+    }
+}
+
+
+/// Parses a unit ID to register file mapping such as `2=inverter.json`
+pub fn parse_unit(val: &str) -> Result<(u8, String), String> {
+    val.split_once('=')
+        .filter(|(_, path)| !path.is_empty())
+        .ok_or_else(|| String::from("The unit must be given as <id>=<file>"))
+        .and_then(|(id, path)| {
+            id.parse::<u8>()
+                .map(|id| (id, path.to_string()))
+                .map_err(|e| format!("Error parsing unit ID: {}", e))
+        })
+}
+
 
 pub fn validate_time(val: &str) -> Result<Duration, String> {
     if let Some(suffix) = val.strip_suffix("ms") {